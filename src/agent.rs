@@ -0,0 +1,271 @@
+use std::{
+    fs,
+    io::{ErrorKind, Read, Write},
+    os::unix::{
+        fs::MetadataExt,
+        net::{UnixListener, UnixStream},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encrypt::{Cipher, EncryptError, EncryptionManager},
+    secret::SecretBytes,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+/// Frames larger than this are rejected, secrets are small.
+const MAX_FRAME: u32 = 1 << 20;
+
+#[derive(Serialize, Deserialize)]
+enum Request {
+    Encrypt(SecretBytes),
+    Decrypt(Vec<u8>),
+    Lock,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Response {
+    Data(SecretBytes),
+    Error(String),
+    Failed(EncryptError),
+    Locked,
+}
+
+fn send_frame<T: Serialize>(stream: &mut UnixStream, value: &T) -> anyhow::Result<()> {
+    // frames may hold secrets
+    let data = SecretBytes::new(bincode::serialize(value)?);
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(&data)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn recv_frame<T: for<'de> Deserialize<'de>>(stream: &mut UnixStream) -> anyhow::Result<T> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME {
+        anyhow::bail!("The agent frame is too large.");
+    }
+    let mut data = SecretBytes::new(vec![0u8; len as usize]);
+    stream.read_exact(&mut data)?;
+    Ok(bincode::deserialize(&data)?)
+}
+
+/// A background process holding the unlocked key, so that the master passphrase is asked once.
+pub struct Agent {
+    manager: Box<EncryptionManager>,
+    idle_timeout: Duration,
+}
+
+impl Agent {
+    /// Creates an [`Agent`] holding the key of `manager` in locked memory.
+    pub fn new(manager: EncryptionManager, idle_timeout: Duration) -> Self {
+        let manager = Box::new(manager);
+        // best effort, the agent still works if the memory lock limit is too low
+        let _ = manager.lock_in_memory();
+        Self {
+            manager,
+            idle_timeout,
+        }
+    }
+
+    /// Starts an agent in the background for the given key and returns once it listens.
+    ///
+    /// The agent is the current executable run with `agent --serve`, the key is handed over on its stdin.
+    pub fn spawn(
+        manager: &EncryptionManager,
+        socket: &Path,
+        idle_timeout: Duration,
+    ) -> anyhow::Result<()> {
+        // checked here as well, since the errors of the agent aren't shown
+        check_socket_dir(socket)?;
+        let mut child = Command::new(std::env::current_exe()?)
+            .arg("agent")
+            .arg("--serve")
+            .arg("--timeout")
+            .arg(idle_timeout.as_secs().to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            // detach from the terminal session so that closing it doesn't stop the agent
+            .process_group(0)
+            .spawn()?;
+
+        // the unwrap is safe because stdin is piped
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(manager.raw())?;
+        drop(stdin);
+
+        let deadline = Instant::now() + IO_TIMEOUT;
+        while AgentClient::connect(socket).is_none() {
+            if let Some(status) = child.try_wait()? {
+                anyhow::bail!("The agent exited with {}.", status);
+            }
+            if Instant::now() > deadline {
+                anyhow::bail!("The agent didn't start listening in time.");
+            }
+            sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    /// Reads the key handed over by [`Agent::spawn`] from stdin.
+    pub fn read_key() -> anyhow::Result<EncryptionManager> {
+        // a key is 32 bytes, so reading it never reallocates
+        let mut key = SecretBytes::new(Vec::with_capacity(64));
+        std::io::stdin().read_to_end(&mut key)?;
+        EncryptionManager::from_raw(&key)
+    }
+
+    /// Listens on the given socket, answering requests until idle for too long or locked.
+    pub fn serve(&self, socket: &Path) -> anyhow::Result<()> {
+        if socket.exists() {
+            if AgentClient::connect(socket).is_some() {
+                anyhow::bail!("An agent is already running.");
+            }
+            // stale socket of an agent that didn't exit cleanly
+            fs::remove_file(socket)?;
+        }
+
+        check_socket_dir(socket)?;
+        // created accessible by the user only, rather than restricted once other users could connect
+        // SAFETY: umask has no preconditions, and the agent runs no other thread creating files
+        let umask = unsafe { libc::umask(0o077) };
+        let listener = UnixListener::bind(socket);
+        // SAFETY: as above
+        unsafe { libc::umask(umask) };
+        let listener = listener?;
+        listener.set_nonblocking(true)?;
+
+        let mut last_used = Instant::now();
+        let result = loop {
+            if last_used.elapsed() > self.idle_timeout {
+                break Ok(());
+            }
+
+            match listener.accept() {
+                Ok((mut stream, _)) => {
+                    last_used = Instant::now();
+                    match self.handle(&mut stream) {
+                        Ok(true) => {}
+                        Ok(false) => break Ok(()),
+                        // a misbehaving client must not stop the agent
+                        Err(_) => {}
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => sleep(POLL_INTERVAL),
+                Err(e) => break Err(e.into()),
+            }
+        };
+
+        let _ = fs::remove_file(socket);
+        result
+    }
+
+    /// Answers a request, returning `false` if the agent was locked.
+    fn handle(&self, stream: &mut UnixStream) -> anyhow::Result<bool> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+
+        let (response, keep_running) = match recv_frame(stream)? {
+            Request::Encrypt(data) => (
+                to_response(self.manager.encrypt(&data).map(SecretBytes::new)),
+                true,
+            ),
+            Request::Decrypt(data) => (to_response(self.manager.decrypt(&data)), true),
+            Request::Lock => (Response::Locked, false),
+        };
+        send_frame(stream, &response)?;
+        Ok(keep_running)
+    }
+}
+
+/// Checks that no other user can replace the socket: its directory must be owned by the user and
+/// writable by them only.
+fn check_socket_dir(socket: &Path) -> anyhow::Result<()> {
+    let dir = match socket.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let metadata = fs::metadata(dir)?;
+    // SAFETY: getuid has no preconditions
+    let uid = unsafe { libc::getuid() };
+    if metadata.uid() != uid || metadata.mode() & 0o022 != 0 {
+        anyhow::bail!(
+            "`{}` must be owned by you and writable by you only for the agent to listen in it, run `chmod 700 {}`.",
+            dir.display(),
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+fn to_response(result: anyhow::Result<SecretBytes>) -> Response {
+    match result {
+        Ok(data) => Response::Data(data),
+        // keep the error typed so that clients can recover from it
+        Err(e) => match e.downcast::<EncryptError>() {
+            Ok(e) => Response::Failed(e),
+            Err(e) => Response::Error(e.to_string()),
+        },
+    }
+}
+
+/// A client of a running [`Agent`], encrypting and decrypting through it.
+pub struct AgentClient {
+    socket: PathBuf,
+}
+
+impl AgentClient {
+    /// Returns a client if an agent listens on the given socket.
+    pub fn connect(socket: &Path) -> Option<Self> {
+        UnixStream::connect(socket).ok()?;
+        Some(Self {
+            socket: socket.to_path_buf(),
+        })
+    }
+
+    fn request(&self, request: &Request) -> anyhow::Result<Response> {
+        let mut stream = UnixStream::connect(&self.socket)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        send_frame(&mut stream, request)?;
+        recv_frame(&mut stream)
+    }
+
+    /// Asks the agent to forget the key and exit.
+    pub fn lock(&self) -> anyhow::Result<()> {
+        match self.request(&Request::Lock)? {
+            Response::Locked => Ok(()),
+            _ => anyhow::bail!("Unexpected response of the agent."),
+        }
+    }
+}
+
+impl Cipher for AgentClient {
+    fn encrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self.request(&Request::Encrypt(SecretBytes::new(data.to_vec())))? {
+            Response::Data(mut data) => Ok(std::mem::take(&mut *data)),
+            Response::Error(e) => anyhow::bail!("The agent failed to encrypt: {}", e),
+            Response::Failed(e) => Err(e.into()),
+            Response::Locked => anyhow::bail!("Unexpected response of the agent."),
+        }
+    }
+
+    fn decrypt(&self, data: &[u8]) -> anyhow::Result<SecretBytes> {
+        match self.request(&Request::Decrypt(data.to_vec()))? {
+            Response::Data(data) => Ok(data),
+            Response::Error(e) => anyhow::bail!("The agent failed to decrypt: {}", e),
+            Response::Failed(e) => Err(e.into()),
+            Response::Locked => anyhow::bail!("Unexpected response of the agent."),
+        }
+    }
+}
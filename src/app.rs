@@ -1,83 +1,1128 @@
-use crate::pty::Size;
+use std::{
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    io::{stdin, stdout, IsTerminal, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+};
+
+#[cfg(unix)]
+use crate::agent::{Agent, AgentClient};
+use crate::pty::{Captured, Login, Size};
 use crate::{
-    select_box::SelectBox, sshconfig::SshConfigItem, terminal::Terminal, CommandBuilder, Db,
-    EncryptionManager, PseudoTerminal,
+    bundle::{Bundle, Conflict},
+    config::Config,
+    encrypt::{hex, Cipher, EncryptError},
+    orphan::find_orphans,
+    passphrase::prompt_passphrase,
+    script::{LoginScript, PASSWORD_SECRET},
+    secret::{into_string, SecretBytes, SecretString},
+    select_box::{fuzzy_match, SelectBox, Selection},
+    sshconfig::SshConfigItem,
+    store::{
+        AgeStore, FileStore, HostKey, PassStore, SecretKey, SecretStore, StoreConfig, STORE_FILES,
+    },
+    terminal::Terminal,
+    theme::ColorSupport,
+    totp::Totp,
+    CommandBuilder, EncryptionManager, PseudoTerminal,
 };
+use serde::Serialize;
 
-const KEY_FILE: &str = "key";
-const DB_FILE: &str = "db";
+pub(crate) const KEY_FILE: &str = "key";
+pub(crate) const NEW_KEY_FILE: &str = "key.new";
+const AGE_DIR: &str = "age";
+#[cfg(unix)]
+pub(crate) const AGENT_SOCKET: &str = "agent.sock";
 pub const CRATE_NAME: &str = env!("CARGO_PKG_NAME");
+const UNLOCK_ATTEMPTS: usize = 3;
+/// The options of `ssh` taking a value, e.g. `-L`.
+const SSH_OPTIONS_WITH_VALUE: &str = "BbcDEeFIiJLlmOoPpQRSWw";
+
+/// Returns the path of the given file in the data directory `dir`, creating the directory if needed.
+fn store_path(dir: &Path, file: &str) -> anyhow::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    Ok(dir.join(file))
+}
+
+/// Expands a leading `~` to the home directory.
+pub(crate) fn expand_tilde(path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Result::Ok(rest) => dirs::home_dir().unwrap().join(rest),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Returns the kinds of secrets stored for the host, e.g. `password`.
+fn stored_kinds(keys: &[SecretKey], item: &SshConfigItem) -> Vec<&'static str> {
+    let host = HostKey::from(item);
+    [
+        (SecretKey::Password(host.clone()), "password"),
+        (SecretKey::Totp(host), "totp"),
+    ]
+    .into_iter()
+    .filter(|(key, _)| keys.contains(key))
+    .map(|(_, kind)| kind)
+    .collect()
+}
+
+/// Returns the kinds of secrets stored for each host, shown in the selector.
+fn marks(keys: &[SecretKey], hosts: &[SshConfigItem]) -> HashMap<SshConfigItem, String> {
+    let mut marks = HashMap::new();
+    for item in hosts {
+        let kinds = stored_kinds(keys, item);
+        if !kinds.is_empty() {
+            marks.insert(item.clone(), kinds.join(", "));
+        }
+    }
+    marks
+}
+
+/// A host as printed by `fssh list --json`.
+#[derive(Serialize)]
+struct ListedHost<'a> {
+    #[serde(flatten)]
+    item: &'a SshConfigItem,
+    stored: Vec<&'static str>,
+}
+
+/// Splits arguments of `ssh` into its options, which must come before the host, and the remote
+/// command following the host.
+fn split_ssh_args(args: &[String]) -> (&[String], &[String]) {
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg == "--" {
+            return (&args[..i], &args[i + 1..]);
+        }
+        let Some(flags) = arg.strip_prefix('-').filter(|flags| !flags.is_empty()) else {
+            break;
+        };
+        // the value of the option is attached, e.g. `-p22`, or the next argument
+        if let Some((j, c)) = flags
+            .char_indices()
+            .find(|(_, c)| SSH_OPTIONS_WITH_VALUE.contains(*c))
+        {
+            if j + c.len_utf8() == flags.len() {
+                i += 1;
+            }
+        }
+        i += 1;
+    }
+    let i = i.min(args.len());
+    (&args[..i], &args[i..])
+}
+
+/// Returns the host whose alias is `query`, or the only host whose alias starts with it, unlike
+/// the fuzzy search which also matches the user and hostname.
+fn alias_match<'a>(hosts: &'a [SshConfigItem], query: &str) -> Option<&'a SshConfigItem> {
+    if let Some(item) = hosts.iter().find(|item| item.host == query) {
+        return Some(item);
+    }
+    let mut prefixed = hosts.iter().filter(|item| item.host.starts_with(query));
+    match (prefixed.next(), prefixed.next()) {
+        (Some(item), None) => Some(item),
+        _ => None,
+    }
+}
+
+/// Asks a yes/no question on the terminal, `no` being the default.
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    print!("{} [y/N] ", question);
+    stdout().flush()?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    Result::Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
+/// Prompts twice for a new passphrase, e.g. `master passphrase`, on the terminal.
+fn prompt_new_passphrase(name: &str) -> anyhow::Result<SecretString> {
+    let passphrase = SecretString::new(rpassword::prompt_password(format!("New {}: ", name))?);
+    if passphrase.is_empty() {
+        anyhow::bail!("The {} can't be empty.", name);
+    }
+    let repeated = SecretString::new(rpassword::prompt_password(format!(
+        "Repeat the {}: ",
+        name
+    ))?);
+    if passphrase != repeated {
+        anyhow::bail!("The passphrases don't match.");
+    }
+    Result::Ok(passphrase)
+}
+
+/// Loads the local key of the data directory `dir`, prompting for the master passphrase if the key
+/// is protected.
+fn unlock(dir: &Path) -> anyhow::Result<EncryptionManager> {
+    Result::Ok(unlock_with_passphrase(dir)?.0)
+}
+
+/// Loads the local key like [`unlock`], also returning the master passphrase if any.
+fn unlock_with_passphrase(dir: &Path) -> anyhow::Result<(EncryptionManager, Option<SecretString>)> {
+    let key_path = store_path(dir, KEY_FILE)?;
+    // a new key would leave the stored secrets undecryptable
+    if !key_path.exists() {
+        if let Some(store) = STORE_FILES
+            .iter()
+            .map(|file| dir.join(file))
+            .find(|path| path.exists())
+        {
+            anyhow::bail!(
+                "The key `{}` is missing, but `{}` holds secrets encrypted with it. Restore the key from a backup, or move the stores away to start over.",
+                key_path.display(),
+                store.display()
+            );
+        }
+    }
+    if !EncryptionManager::is_protected(&key_path) {
+        return Result::Ok((EncryptionManager::new(key_path)?, None));
+    }
+
+    let mut error = None;
+    let mut attempts = 0;
+    loop {
+        let Some(passphrase) = prompt_passphrase("Master passphrase", error.as_deref())? else {
+            anyhow::bail!("Unlocking was cancelled.");
+        };
+        match EncryptionManager::unlock(&key_path, &passphrase) {
+            Result::Ok(manager) => return Result::Ok((manager, Some(passphrase))),
+            Err(e) => {
+                attempts += 1;
+                // another passphrase doesn't repair the key
+                let damaged = matches!(
+                    e.downcast_ref::<EncryptError>(),
+                    Some(EncryptError::Damaged(_))
+                );
+                if damaged || attempts >= UNLOCK_ATTEMPTS {
+                    return Err(e);
+                }
+                error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+/// The cipher of the stored secrets, resolved on first use so that listing them needs no key:
+/// the running agent if any, otherwise the local key.
+struct LazyCipher {
+    dir: PathBuf,
+    cipher: OnceCell<Box<dyn Cipher>>,
+}
+
+impl LazyCipher {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            cipher: OnceCell::new(),
+        }
+    }
+
+    fn resolve(&self) -> anyhow::Result<&dyn Cipher> {
+        if let Some(cipher) = self.cipher.get() {
+            return Result::Ok(cipher.as_ref());
+        }
+
+        #[cfg(unix)]
+        let agent = AgentClient::connect(&store_path(&self.dir, AGENT_SOCKET)?);
+        #[cfg(not(unix))]
+        let agent: Option<EncryptionManager> = None;
+
+        let cipher: Box<dyn Cipher> = match agent {
+            Some(agent) => Box::new(agent),
+            None => Box::new(unlock(&self.dir)?),
+        };
+        Result::Ok(self.cipher.get_or_init(|| cipher).as_ref())
+    }
+}
+
+impl Cipher for LazyCipher {
+    fn encrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.resolve()?.encrypt(data)
+    }
+
+    fn decrypt(&self, data: &[u8]) -> anyhow::Result<SecretBytes> {
+        self.resolve()?.decrypt(data)
+    }
+}
+
+/// How the hosts are searched before the selector opens, see [`App::run`].
+#[derive(Debug, Default, Clone)]
+pub struct Search {
+    /// A fuzzy query matched against the alias, user and hostname of the hosts.
+    pub query: String,
+    /// Connects without the selector to the host whose alias is the query, or to the only host
+    /// matching it.
+    pub select_one: bool,
+    /// Connects without the selector to the host whose alias is the query, or to the only host
+    /// whose alias starts with it.
+    pub select_alias: bool,
+    /// Fails without the selector when the query matches no host.
+    pub exit_zero: bool,
+}
 
 /// The entry to the CLI.
 pub struct App {
+    hosts: Vec<SshConfigItem>,
     select_box: SelectBox,
+    config: Config,
+    // the directory of the key, the default store and the agent socket
+    dir: PathBuf,
+    cipher: Rc<LazyCipher>,
+    ssh_args: Vec<String>,
 }
 
 impl App {
     /// Create a new [`App`] instance.
-    pub fn new(data: Vec<SshConfigItem>) -> Self {
+    pub fn new(data: Vec<SshConfigItem>, config: Config) -> Self {
+        let dir = config.data_dir();
+        let mut select_box = SelectBox::new(data.clone());
+        select_box.set_keymap(config.selector_keymap());
+        select_box.set_theme(config.theme());
+        // crossterm drops every color when `NO_COLOR` is set, unless the colors are configured
+        if !matches!(config.theme.colors, ColorSupport::Auto | ColorSupport::None) {
+            crossterm::style::force_color_output(true);
+        }
         App {
-            select_box: SelectBox::new(data),
+            hosts: data,
+            select_box,
+            config,
+            cipher: Rc::new(LazyCipher::new(dir.clone())),
+            dir,
+            ssh_args: Vec::new(),
+        }
+    }
+
+    /// Sets the arguments passed to `ssh` in addition to the ones of the configuration: options,
+    /// then the remote command to run.
+    pub fn set_ssh_args(&mut self, args: Vec<String>) {
+        self.ssh_args = args;
+    }
+
+    /// Unlocks the key and starts an agent holding it until idle for `timeout` seconds.
+    #[cfg(unix)]
+    pub fn start_agent(&mut self, timeout: Option<u64>) -> anyhow::Result<()> {
+        let socket = store_path(&self.dir, AGENT_SOCKET)?;
+        if AgentClient::connect(&socket).is_some() {
+            println!("The agent is already running.");
+            return Result::Ok(());
+        }
+
+        let timeout = timeout.unwrap_or(self.config.agent_timeout);
+        let manager = unlock(&self.dir)?;
+        Agent::spawn(&manager, &socket, std::time::Duration::from_secs(timeout))?;
+        println!(
+            "The agent is running, it locks after {}s of inactivity.",
+            timeout
+        );
+        Result::Ok(())
+    }
+
+    /// Serves the key handed over on stdin, run in the process started by [`App::start_agent`].
+    #[cfg(unix)]
+    pub fn serve_agent(&mut self, timeout: Option<u64>) -> anyhow::Result<()> {
+        let timeout = timeout.unwrap_or(self.config.agent_timeout);
+        let agent = Agent::new(Agent::read_key()?, std::time::Duration::from_secs(timeout));
+        agent.serve(&store_path(&self.dir, AGENT_SOCKET)?)
+    }
+
+    /// Makes the running agent forget the key.
+    #[cfg(unix)]
+    pub fn lock_agent(&mut self) -> anyhow::Result<()> {
+        match AgentClient::connect(&store_path(&self.dir, AGENT_SOCKET)?) {
+            Some(agent) => {
+                agent.lock()?;
+                println!("Locked.");
+            }
+            None => println!("No agent is running."),
+        }
+        Result::Ok(())
+    }
+
+    /// Protects the encryption key with a master passphrase, or changes it, prompting for it on the
+    /// terminal. An existing unprotected key is wrapped in place.
+    ///
+    /// If `remove` is set, the key is stored unprotected again.
+    pub fn set_master_passphrase(&mut self, remove: bool) -> anyhow::Result<()> {
+        let manager = unlock(&self.dir)?;
+        let key_path = store_path(&self.dir, KEY_FILE)?;
+
+        if remove {
+            manager.unprotect(key_path)?;
+            println!("Removed the master passphrase.");
+            return Result::Ok(());
+        }
+
+        let passphrase = prompt_new_passphrase("master passphrase")?;
+        manager.protect(key_path, &passphrase)?;
+        println!("The key is now protected by the master passphrase.");
+
+        Result::Ok(())
+    }
+
+    /// Generates a new encryption key and re-encrypts every stored secret with it.
+    ///
+    /// The new key is written to a separate file first and replaces the old one once all the secrets
+    /// are re-encrypted, so that an interrupted rotation is resumed by running it again.
+    pub fn rotate_key(&mut self) -> anyhow::Result<()> {
+        if !matches!(self.config.store, StoreConfig::File) {
+            anyhow::bail!(
+                "Only the default store is encrypted with the fssh key, rotate the keys of the configured store with its own tools."
+            );
+        }
+
+        let key_path = store_path(&self.dir, KEY_FILE)?;
+        let new_key_path = store_path(&self.dir, NEW_KEY_FILE)?;
+        let (old, passphrase) = unlock_with_passphrase(&self.dir)?;
+
+        let new = if new_key_path.exists() {
+            println!("Resuming the interrupted key rotation.");
+            match passphrase.as_deref() {
+                Some(passphrase) => EncryptionManager::unlock(&new_key_path, passphrase)?,
+                None => EncryptionManager::new(&new_key_path)?,
+            }
+        } else {
+            let new = EncryptionManager::generate();
+            match passphrase.as_deref() {
+                Some(passphrase) => new.protect(&new_key_path, passphrase)?,
+                None => new.unprotect(&new_key_path)?,
+            }
+            new
+        };
+
+        let new = Rc::new(new);
+        let mut store = FileStore::open(self.data_dir()?, Rc::new(old), &self.hosts)?;
+        store.rotate(new.clone())?;
+        store.flush()?;
+        std::fs::rename(&new_key_path, &key_path)?;
+        println!("Rotated the key, its id is now {}.", hex(&new.key_id()));
+
+        // the agent still holds the old key
+        #[cfg(unix)]
+        if let Some(agent) = AgentClient::connect(&store_path(&self.dir, AGENT_SOCKET)?) {
+            agent.lock()?;
+            println!("Locked the agent, which held the old key.");
+        }
+        self.cipher = Rc::new(LazyCipher::new(self.dir.clone()));
+
+        Result::Ok(())
+    }
+
+    /// Returns the secret stored for `key`, or `None` with a notice for the user if it can't be decrypted.
+    fn recover(
+        &self,
+        store: &mut dyn SecretStore,
+        key: &SecretKey,
+        hint: &str,
+        notices: &mut Vec<String>,
+    ) -> anyhow::Result<Option<SecretBytes>> {
+        match store.get(key) {
+            Result::Ok(secret) => Result::Ok(secret),
+            Err(e) => {
+                let Some(error) = e.downcast_ref::<EncryptError>() else {
+                    return Err(e);
+                };
+                let mut message = format!("Can't decrypt `{}`: {} {}", key.name(), error, hint);
+                if matches!(error, EncryptError::OtherKey { .. })
+                    && store_path(&self.dir, NEW_KEY_FILE)?.exists()
+                {
+                    message.push_str(
+                        " A key rotation was interrupted, run `fssh rotate-key` to finish it.",
+                    );
+                }
+                notices.push(message);
+                Result::Ok(None)
+            }
+        }
+    }
+
+    /// Returns the data directory, creating it if needed.
+    fn data_dir(&self) -> anyhow::Result<&Path> {
+        std::fs::create_dir_all(&self.dir)?;
+        Result::Ok(&self.dir)
+    }
+
+    /// Returns the host with the given alias.
+    fn find_host(&self, alias: &str) -> anyhow::Result<SshConfigItem> {
+        self.hosts
+            .iter()
+            .find(|item| item.host == alias)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No host named `{}` in the SSH config.", alias))
+    }
+
+    /// Opens the secret store selected in the configuration.
+    fn store(&self) -> anyhow::Result<Box<dyn SecretStore>> {
+        let store: Box<dyn SecretStore> = match self.config.store.clone() {
+            StoreConfig::File => Box::new(FileStore::open(
+                self.data_dir()?,
+                self.cipher.clone(),
+                &self.hosts,
+            )?),
+            StoreConfig::Pass { prefix } => Box::new(PassStore::new(prefix)),
+            StoreConfig::Age {
+                recipients,
+                identity,
+                dir,
+            } => {
+                let dir = match dir {
+                    Some(dir) => expand_tilde(&dir),
+                    None => store_path(&self.dir, AGE_DIR)?,
+                };
+                Box::new(AgeStore::new(recipients, expand_tilde(&identity), dir))
+            }
+        };
+        Result::Ok(store)
+    }
+
+    /// Enrolls the TOTP secret of the host with the given alias, prompting for it on the terminal.
+    ///
+    /// If `remove` is set, the enrolled secret is removed instead.
+    pub fn enroll_totp(&mut self, alias: &str, remove: bool) -> anyhow::Result<()> {
+        let key = SecretKey::Totp(HostKey::from(&self.find_host(alias)?));
+
+        if remove {
+            let mut store = self.store()?;
+            if !store.remove(&key)? {
+                anyhow::bail!("No TOTP secret enrolled for `{}`.", alias);
+            }
+            store.flush()?;
+            println!("Removed the TOTP secret of `{}`.", alias);
+            return Result::Ok(());
         }
+
+        let secret = SecretString::new(rpassword::prompt_password(format!(
+            "TOTP secret (base32) for `{}`: ",
+            alias
+        ))?);
+        let totp = Totp::from_base32(&secret)?;
+        let mut store = self.store()?;
+        store.set(&key, totp.secret())?;
+        store.flush()?;
+        println!("Enrolled. Current verification code: {}", totp.now()?);
+
+        Result::Ok(())
+    }
+
+    /// Stores a named secret referred to by login scripts, prompting for it on the terminal.
+    ///
+    /// If `remove` is set, the secret is removed instead.
+    pub fn store_secret(&mut self, name: &str, remove: bool) -> anyhow::Result<()> {
+        if name == PASSWORD_SECRET {
+            anyhow::bail!(
+                "`{}` refers to the login password of the host and can't be set.",
+                PASSWORD_SECRET
+            );
+        }
+        let key = SecretKey::Named(name.to_string());
+
+        if remove {
+            let mut store = self.store()?;
+            if !store.remove(&key)? {
+                anyhow::bail!("No secret named `{}`.", name);
+            }
+            store.flush()?;
+            println!("Removed the secret `{}`.", name);
+            return Result::Ok(());
+        }
+
+        let secret = SecretString::new(rpassword::prompt_password(format!("Secret `{}`: ", name))?);
+        let mut store = self.store()?;
+        store.set(&key, secret.as_bytes())?;
+        store.flush()?;
+        println!("Stored the secret `{}`.", name);
+
+        Result::Ok(())
     }
 
     /// Provide a TUI interface for selecting an SSH server.
-    fn select(&mut self) -> anyhow::Result<Option<SshConfigItem>> {
+    fn select(&mut self) -> anyhow::Result<Option<Selection>> {
         let mut terminal = Terminal::new(Some(self.select_box.len() as u16 + 5), false)?;
         let selected = self.select_box.select(&mut terminal)?;
         Result::Ok(selected)
     }
 
-    /// Run the whole application.
-    pub fn run(&mut self) -> anyhow::Result<()> {
-        // select a host
-        if let Some(item) = self.select()? {
-            let db_path = dirs::config_dir().unwrap().join(CRATE_NAME).join(DB_FILE);
-            let key_path = dirs::config_dir().unwrap().join(CRATE_NAME).join(KEY_FILE);
+    /// Offers to move the secrets of hosts missing from the SSH config to the closest current host,
+    /// before the selector opens.
+    fn reassociate_orphans(&self, store: &mut dyn SecretStore) -> anyhow::Result<()> {
+        let orphans = find_orphans(&store.keys()?, &self.hosts);
+        let mut remaining = 0;
+        for orphan in orphans.iter() {
+            let (kind, host) = match &orphan.key {
+                SecretKey::Password(host) => ("password", host),
+                SecretKey::Totp(host) => ("TOTP secret", host),
+                _ => continue,
+            };
+            let Some(candidate) = orphan.candidate.as_ref().filter(|_| stdin().is_terminal())
+            else {
+                remaining += 1;
+                continue;
+            };
+
+            let question = format!(
+                "The {} of {} matches no host of the SSH config. Move it to {}?",
+                kind, host, candidate
+            );
+            if confirm(&question)? && orphan.reassociate(store)? {
+                println!("Moved.");
+            } else {
+                remaining += 1;
+            }
+        }
+        store.flush()?;
+
+        if remaining > 0 {
+            eprintln!(
+                "{} stored secret(s) match no host of the SSH config, `fssh gc` removes them.",
+                remaining
+            );
+        }
+        Result::Ok(())
+    }
 
-            if let Some(parent) = db_path.parent() {
-                std::fs::create_dir_all(parent)?;
+    /// Forgets the passwords and passphrases stored for longer than the expiry policy allows, so
+    /// that they are typed again.
+    ///
+    /// TOTP and named secrets are enrolled with their command rather than typed at a login, so they
+    /// never expire.
+    fn purge_expired(&self, store: &mut dyn SecretStore) -> anyhow::Result<()> {
+        let now = crate::store::now();
+        for key in store.keys()? {
+            let (expiry, secret) = match &key {
+                SecretKey::Password(host) => (
+                    self.config.expiry_for(host),
+                    format!("password of {}", host),
+                ),
+                SecretKey::Passphrase(path) => (
+                    self.config.expiry(),
+                    format!("passphrase of `{}`", path.display()),
+                ),
+                _ => continue,
+            };
+            let Some(stamps) = store.stamps(&key)? else {
+                continue;
+            };
+            if expiry.is_expired(&stamps, now) {
+                store.remove(&key)?;
+                println!("The stored {} expired, it will be asked again.", secret);
             }
+        }
+        store.flush()
+    }
 
-            let mut db: Db<SshConfigItem, Vec<u8>> = Db::open(db_path)?;
-            let manager = EncryptionManager::new(key_path)?;
+    /// Removes the secrets of hosts missing from the SSH config and of identity files that don't exist.
+    ///
+    /// If `dry_run` is set, they are only listed.
+    pub fn gc(&self, dry_run: bool) -> anyhow::Result<()> {
+        let mut store = self.store()?;
+        let keys = store.keys()?;
+        let orphans = find_orphans(&keys, &self.hosts);
+        if orphans.is_empty() {
+            println!("Nothing to remove.");
+        } else {
+            for orphan in orphans.iter() {
+                if !dry_run {
+                    store.remove(&orphan.key)?;
+                }
+                println!("{}", orphan.key.name());
+            }
+            store.flush()?;
 
-            // check if the password is already stored
-            let passwd = if let Some(passwd) = db.get(&item) {
-                let passwd = manager.decrypt(&passwd)?;
-                self.connect(&item, Some(String::from_utf8(passwd)?))?
+            if dry_run {
+                println!("{} secret(s) would be removed.", orphans.len());
             } else {
-                self.connect(&item, None)?
-            };
+                println!("Removed {} secret(s).", orphans.len());
+            }
+        }
 
-            // update the password
-            if let Some(passwd) = passwd {
-                db.insert(item, manager.encrypt(passwd.as_bytes())?);
-                db.flush()?;
+        let enrolled = keys
+            .iter()
+            .filter(|key| matches!(key, SecretKey::Totp(_) | SecretKey::Named(_)))
+            .filter(|key| !orphans.iter().any(|orphan| orphan.key == **key))
+            .count();
+        if enrolled > 0 {
+            println!(
+                "{} TOTP or named secret(s) never expire, `fssh totp <alias> --remove` and `fssh secret <name> --remove` remove them.",
+                enrolled
+            );
+        }
+        Result::Ok(())
+    }
+
+    /// Writes every stored secret to a bundle protected by a passphrase, prompting for it on the
+    /// terminal.
+    pub fn export(&self, path: &Path) -> anyhow::Result<()> {
+        let mut store = self.store()?;
+        let mut entries = Vec::new();
+        let mut notices = Vec::new();
+        for key in store.keys()? {
+            let hint = "It is left out of the export.";
+            if let Some(secret) = self.recover(store.as_mut(), &key, hint, &mut notices)? {
+                let stamps = store.stamps(&key)?;
+                entries.push((key, secret, stamps));
             }
         }
+        for notice in notices {
+            println!("{}", notice);
+        }
+
+        let passphrase = prompt_new_passphrase("export passphrase")?;
+        let count = entries.len();
+        Bundle::new(entries).write(path, &passphrase)?;
+        println!("Exported {} secret(s) to `{}`.", count, path.display());
 
         Result::Ok(())
     }
 
-    /// Spawn a new TTY and run the SSH client to connect to the chosen host.
-    fn connect(
+    /// Merges the secrets of a bundle written by [`App::export`] into the store, resolving the ones
+    /// that differ from the stored ones according to `conflict`.
+    pub fn import(&self, path: &Path, conflict: Conflict) -> anyhow::Result<()> {
+        let passphrase = SecretString::new(rpassword::prompt_password(format!(
+            "Passphrase of `{}`: ",
+            path.display()
+        ))?);
+        let bundle = Bundle::read(path, &passphrase)?;
+        let ask = conflict == Conflict::Ask && stdin().is_terminal();
+
+        let mut store = self.store()?;
+        let stored: HashSet<SecretKey> = store.keys()?.into_iter().collect();
+        let (mut added, mut replaced, mut kept) = (0, 0, 0);
+        for (key, secret, stamps) in bundle.entries() {
+            if stored.contains(key) {
+                let mut notices = Vec::new();
+                let hint = "The imported one replaces it.";
+                let current = self.recover(store.as_mut(), key, hint, &mut notices)?;
+                for notice in notices {
+                    println!("{}", notice);
+                }
+                if let Some(current) = current {
+                    if current == *secret {
+                        continue;
+                    }
+                    let replace = match conflict {
+                        Conflict::Replace => true,
+                        Conflict::Ask if ask => confirm(&format!(
+                            "`{}` is already stored with another value. Replace it?",
+                            key.name()
+                        ))?,
+                        _ => false,
+                    };
+                    if !replace {
+                        kept += 1;
+                        continue;
+                    }
+                }
+                replaced += 1;
+            } else {
+                added += 1;
+            }
+            // the expiry policy counts from when the secret was first stored
+            match stamps {
+                Some(stamps) => store.set_with_stamps(key, secret, *stamps)?,
+                None => store.set(key, secret)?,
+            }
+        }
+        store.flush()?;
+
+        println!(
+            "Imported {} new secret(s), replaced {} and kept {} differing one(s).",
+            added, replaced, kept
+        );
+        Result::Ok(())
+    }
+
+    /// Prints the hosts of the SSH config and the kinds of secrets stored for them, as a table or
+    /// as JSON.
+    pub fn list(&self, json: bool) -> anyhow::Result<()> {
+        let keys = self.store()?.keys()?;
+        if json {
+            let hosts: Vec<ListedHost> = self
+                .hosts
+                .iter()
+                .map(|item| ListedHost {
+                    item,
+                    stored: stored_kinds(&keys, item),
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&hosts)?);
+            return Result::Ok(());
+        }
+
+        let rows: Vec<[String; 5]> = self
+            .hosts
+            .iter()
+            .map(|item| {
+                [
+                    item.host.clone(),
+                    item.user.clone(),
+                    item.hostname.clone(),
+                    item.port.to_string(),
+                    stored_kinds(&keys, item).join(", "),
+                ]
+            })
+            .collect();
+        let header = ["HOST", "USER", "HOSTNAME", "PORT", "STORED"].map(String::from);
+        let mut widths = [0; 5];
+        for row in std::iter::once(&header).chain(rows.iter()) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        for row in std::iter::once(&header).chain(rows.iter()) {
+            let line: Vec<String> = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            println!("{}", line.join("  ").trim_end());
+        }
+        Result::Ok(())
+    }
+
+    /// Connects to the host with the given alias without the selector, returning the exit code of
+    /// the SSH client.
+    pub fn connect_host(&mut self, alias: &str) -> anyhow::Result<Option<u32>> {
+        let item = self.find_host(alias)?;
+        let mut store = self.store()?;
+        self.purge_expired(store.as_mut())?;
+        self.login(store.as_mut(), item)
+    }
+
+    /// Forgets the stored password of the host with the given alias.
+    pub fn forget(&mut self, alias: &str) -> anyhow::Result<()> {
+        let key = SecretKey::Password(HostKey::from(&self.find_host(alias)?));
+        let mut store = self.store()?;
+        if !store.remove(&key)? {
+            anyhow::bail!("No password stored for `{}`.", alias);
+        }
+        store.flush()?;
+        println!("Forgot the password of `{}`.", alias);
+        Result::Ok(())
+    }
+
+    /// Prints the hosts matching `query` like the selector, one per line, as their alias or as
+    /// `format` filled by [`SshConfigItem::format`].
+    ///
+    /// Returns whether some host matched.
+    pub fn filter(&self, query: &str, format: Option<&str>) -> bool {
+        let matches = fuzzy_match(&self.hosts, query);
+        for (item, _) in matches.iter() {
+            match format {
+                Some(format) => println!("{}", item.format(format)),
+                None => println!("{}", item.host),
+            }
+        }
+        !matches.is_empty()
+    }
+
+    /// Run the whole application, the selector starting with the query of `search`.
+    pub fn run(&mut self, search: &Search) -> anyhow::Result<()> {
+        let alias = if search.select_alias {
+            alias_match(&self.hosts, &search.query)
+        } else {
+            self.hosts.iter().find(|item| item.host == search.query)
+        };
+        if let Some(alias) = alias.filter(|_| search.select_one || search.select_alias) {
+            let alias = alias.host.clone();
+            self.connect_host(&alias)?;
+            return Result::Ok(());
+        }
+        if search.select_one || search.exit_zero {
+            let matches = fuzzy_match(&self.hosts, &search.query);
+            match matches.as_slice() {
+                [] if search.exit_zero => {
+                    anyhow::bail!("No host matches `{}`.", search.query)
+                }
+                [(item, _)] if search.select_one => {
+                    let alias = item.host.clone();
+                    self.connect_host(&alias)?;
+                    return Result::Ok(());
+                }
+                _ => {}
+            }
+        }
+
+        let mut store = self.store()?;
+        self.reassociate_orphans(store.as_mut())?;
+        self.purge_expired(store.as_mut())?;
+        self.select_box.set_query(&search.query);
+
+        // select a host, managing its password until one is picked
+        loop {
+            self.select_box
+                .set_marks(marks(&store.keys()?, &self.hosts));
+            // the master passphrase confirms revealing a password, when there is one
+            self.select_box
+                .set_confirm_reveal(!self.reveal_asks_passphrase());
+            let Some(selection) = self.select()? else {
+                return Result::Ok(());
+            };
+            let status: SecretString = match selection {
+                Selection::Connect(item) => {
+                    self.login(store.as_mut(), item)?;
+                    return Result::Ok(());
+                }
+                Selection::Forget(item) => {
+                    if store.remove(&SecretKey::Password(HostKey::from(&item)))? {
+                        store.flush()?;
+                        format!("Forgot the password of `{}`.", item.host).into()
+                    } else {
+                        format!("No password stored for `{}`.", item.host).into()
+                    }
+                }
+                Selection::Edit(item) => {
+                    let title = format!("New password for `{}`", item.host);
+                    match prompt_passphrase(&title, None)? {
+                        Some(passwd) => {
+                            store.set(
+                                &SecretKey::Password(HostKey::from(&item)),
+                                passwd.as_bytes(),
+                            )?;
+                            store.flush()?;
+                            format!("Stored the password of `{}`.", item.host).into()
+                        }
+                        None => "Cancelled.".to_string().into(),
+                    }
+                }
+                Selection::Reveal(item) => self.reveal(store.as_mut(), &item)?,
+            };
+            self.select_box.set_status(status);
+        }
+    }
+
+    /// Returns whether revealing a password asks for the master passphrase, which protects the key
+    /// of the default store.
+    fn reveal_asks_passphrase(&self) -> bool {
+        matches!(self.config.store, StoreConfig::File)
+            && EncryptionManager::is_protected(self.dir.join(KEY_FILE))
+    }
+
+    /// Returns the message showing the stored password of the host, after confirming the master
+    /// passphrase if the key is protected by one; the selector asks for a confirmation otherwise.
+    fn reveal(
         &self,
+        store: &mut dyn SecretStore,
         item: &SshConfigItem,
-        passwd: Option<String>,
-    ) -> anyhow::Result<Option<String>> {
+    ) -> anyhow::Result<SecretString> {
+        if self.reveal_asks_passphrase() {
+            let title = "Master passphrase to reveal the password";
+            let Some(passphrase) = prompt_passphrase(title, None)? else {
+                return Result::Ok("Cancelled.".to_string().into());
+            };
+            if EncryptionManager::unlock(self.dir.join(KEY_FILE), &passphrase).is_err() {
+                return Result::Ok("Wrong master passphrase.".to_string().into());
+            }
+        }
+
+        let mut notices = Vec::new();
+        let key = SecretKey::Password(HostKey::from(item));
+        let status = match self.recover(store, &key, "Press (e) to type it again.", &mut notices)? {
+            Some(passwd) => {
+                let passwd = into_string(passwd)?;
+                let label = format!("Password of `{}`: ", item.host);
+                // allocated once, so that no copy of the password is left behind by a reallocation
+                let mut status =
+                    SecretString::new(String::with_capacity(label.len() + passwd.len()));
+                status.push_str(&label);
+                status.push_str(&passwd);
+                status
+            }
+            None => notices
+                .pop()
+                .unwrap_or_else(|| format!("No password stored for `{}`.", item.host))
+                .into(),
+        };
+        Result::Ok(status)
+    }
+
+    /// Logs into the selected host with the stored secrets and stores the ones typed meanwhile,
+    /// returning the exit code of the SSH client.
+    fn login(
+        &self,
+        store: &mut dyn SecretStore,
+        item: SshConfigItem,
+    ) -> anyhow::Result<Option<u32>> {
+        let mut notices = Vec::new();
+
+        // check if the secrets are already stored, a secret that can't be decrypted is typed again
+        let passwd = self
+            .recover(
+                store,
+                &SecretKey::Password(HostKey::from(&item)),
+                "Type the password to replace it.",
+                &mut notices,
+            )?
+            .map(|passwd| into_string(passwd).map(Arc::new))
+            .transpose()?;
+        let keys = store.keys()?;
+        let mut passphrases = HashMap::new();
+        for key in keys.iter() {
+            if let SecretKey::Passphrase(path) = key {
+                let hint = "Type the passphrase to replace it.";
+                if let Some(passphrase) = self.recover(store, key, hint, &mut notices)? {
+                    passphrases.insert(path.clone(), into_string(passphrase)?);
+                }
+            }
+        }
+        let hint = format!("Enroll it again with `fssh totp {}`.", item.host);
+        let totp = self
+            .recover(
+                store,
+                &SecretKey::Totp(HostKey::from(&item)),
+                &hint,
+                &mut notices,
+            )?
+            .map(Totp::new);
+
+        let steps = self.config.script_for(&item);
+        let script = if steps.is_empty() {
+            None
+        } else {
+            let mut secrets = HashMap::new();
+            for key in keys.iter() {
+                if let SecretKey::Named(name) = key {
+                    let hint = format!("Store it again with `fssh secret {}`.", name);
+                    if let Some(secret) = self.recover(store, key, &hint, &mut notices)? {
+                        secrets.insert(name.clone(), Arc::new(into_string(secret)?));
+                    }
+                }
+            }
+            // shared rather than copied
+            if let Some(passwd) = passwd.as_ref() {
+                secrets.insert(PASSWORD_SECRET.to_string(), Arc::clone(passwd));
+            }
+            Some(LoginScript::new(steps, &secrets)?)
+        };
+
+        let login = Login {
+            user: item.user.clone(),
+            passwd,
+            passphrases,
+            totp,
+            script,
+            sudo: self.config.sudo_for(&item),
+            prompts: self.config.prompts_for(&item)?,
+            notices,
+            keys: self.config.terminal_keymap(),
+            theme: self.config.theme(),
+        };
+        let captured = self.connect(&item, login)?;
+
+        // update the secrets, and when the stored ones were last used to log in
+        let passwd_key = SecretKey::Password(HostKey::from(&item));
+        match captured.passwd {
+            Some(passwd) => store.set(&passwd_key, passwd.as_bytes())?,
+            None if captured.used_passwd => store.touch(&passwd_key)?,
+            None => {}
+        }
+        for path in captured.used_passphrases {
+            store.touch(&SecretKey::Passphrase(path))?;
+        }
+        for (path, passphrase) in captured.passphrases {
+            store.set(&SecretKey::Passphrase(path), passphrase.as_bytes())?;
+        }
+        store.flush()?;
+
+        Result::Ok(captured.exit_code)
+    }
+
+    /// Spawn a new TTY and run the SSH client to connect to the chosen host.
+    ///
+    /// The client is `ssh` unless the configuration sets another command for the host, which then
+    /// gets the extra arguments at its end.
+    fn connect(&self, item: &SshConfigItem, login: Login) -> anyhow::Result<Captured> {
         let mut terminal = Terminal::new(None, true)?;
-        let mut cmd = CommandBuilder::new("ssh");
-        cmd.arg(&item.host);
+        let cmd = match self.config.client_for(item)? {
+            Some(client) => {
+                let mut cmd = CommandBuilder::new(&client[0]);
+                cmd.args(&client[1..]);
+                cmd.args(&self.ssh_args);
+                cmd
+            }
+            None => {
+                let mut cmd = CommandBuilder::new("ssh");
+                let (options, command) = split_ssh_args(&self.ssh_args);
+                cmd.args(self.config.ssh_args_for(item));
+                cmd.args(options);
+                cmd.arg(&item.host);
+                cmd.args(command);
+                cmd
+            }
+        };
 
         let size = Size::new(terminal.size()?.height, terminal.size()?.width);
 
         let rt = tokio::runtime::Runtime::new()?;
-        let passwd = rt.block_on(async move {
-            let mut pty = PseudoTerminal::new(size, cmd, passwd)?;
+        let captured = rt.block_on(async move {
+            let mut pty = PseudoTerminal::new(size, cmd, login)?;
             pty.run(&mut terminal).await
         })?;
 
-        Result::Ok(passwd)
+        Result::Ok(captured)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_split(args: &[&str], options: &[&str], command: &[&str]) {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let split = split_ssh_args(&args);
+        assert_eq!(split.0, options, "{:?}", args);
+        assert_eq!(split.1, command, "{:?}", args);
+    }
+
+    #[test]
+    fn splits_ssh_options_from_the_command() {
+        assert_split(&["-p", "22", "uptime"], &["-p", "22"], &["uptime"]);
+        assert_split(&["-p22", "uptime"], &["-p22"], &["uptime"]);
+        assert_split(
+            &["-oFoo=bar", "-v", "ls", "-l"],
+            &["-oFoo=bar", "-v"],
+            &["ls", "-l"],
+        );
+        assert_split(
+            &["-J", "host", "-vp", "2222", "ls"],
+            &["-J", "host", "-vp", "2222"],
+            &["ls"],
+        );
+        assert_split(&["-A", "-t"], &["-A", "-t"], &[]);
+        assert_split(&["-p"], &["-p"], &[]);
+        assert_split(&[], &[], &[]);
+    }
+
+    #[test]
+    fn matches_an_exact_or_unique_alias() {
+        let host = |alias: &str, user: &str, hostname: &str| SshConfigItem {
+            host: alias.to_string(),
+            user: user.to_string(),
+            hostname: hostname.to_string(),
+            port: 22,
+        };
+        let hosts = [
+            host("prod", "admin", "10.0.0.4"),
+            host("prod-db", "admin", "10.0.0.5"),
+            host("prod-web", "deploy", "web.example.com"),
+            host("staging", "deploy", "db.example.com"),
+        ];
+        let alias = |query| alias_match(&hosts, query).map(|item| item.host.as_str());
+        assert_eq!(alias("prod"), Some("prod"));
+        assert_eq!(alias("prod-d"), Some("prod-db"));
+        assert_eq!(alias("st"), Some("staging"));
+        assert_eq!(alias("prod-"), None);
+        // the fuzzy search would match the user or hostname, or skip characters
+        assert_eq!(alias("deploy"), None);
+        assert_eq!(alias("example"), None);
+        assert_eq!(alias("pdb"), None);
+        assert_eq!(alias("db"), None);
+    }
+
+    #[test]
+    fn double_dash_ends_the_options() {
+        assert_split(&["-v", "--", "-x", "y"], &["-v"], &["-x", "y"]);
+        assert_split(&["--", "--"], &[], &["--"]);
+        // a value looking like the end of the options
+        assert_split(&["-o", "--", "ls"], &["-o", "--"], &["ls"]);
     }
 }
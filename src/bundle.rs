@@ -0,0 +1,168 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::{
+    encrypt::{seal, unseal, write_atomically, EncryptError},
+    secret::SecretBytes,
+    store::{SecretKey, Stamps},
+};
+
+/// Magic bytes starting the bundle files, followed by the format version.
+const BUNDLE_MAGIC: &[u8] = b"FSSHBUNDLE";
+const BUNDLE_VERSION: u16 = 1;
+
+/// How an imported secret replaces a different one stored under the same key.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Conflict {
+    /// Ask for each secret, keeping the stored one when not run in a terminal.
+    #[default]
+    Ask,
+    /// Keep the stored secret.
+    Keep,
+    /// Replace the stored secret with the imported one.
+    Replace,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    name: String,
+    secret: SecretBytes,
+    stamps: Option<Stamps>,
+}
+
+/// Secrets sealed with a passphrase instead of the fssh key, to move them to another machine.
+pub struct Bundle {
+    entries: Vec<(SecretKey, SecretBytes, Option<Stamps>)>,
+}
+
+impl Bundle {
+    /// Creates a bundle of the given secrets, along with when they were stored and last used if
+    /// known.
+    pub fn new(entries: Vec<(SecretKey, SecretBytes, Option<Stamps>)>) -> Self {
+        Self { entries }
+    }
+
+    /// Returns the secrets of the bundle.
+    pub fn entries(&self) -> &[(SecretKey, SecretBytes, Option<Stamps>)] {
+        &self.entries
+    }
+
+    /// Writes the bundle encrypted with a key derived from `passphrase`, only readable by the user.
+    pub fn write<P: AsRef<Path>>(&self, path: P, passphrase: &str) -> anyhow::Result<()> {
+        let entries: Vec<Entry> = self
+            .entries
+            .iter()
+            .map(|(key, secret, stamps)| Entry {
+                name: portable(key).name(),
+                secret: secret.clone(),
+                stamps: *stamps,
+            })
+            .collect();
+        let plaintext = Zeroizing::new(bincode::serialize(&entries)?);
+
+        let mut data = BUNDLE_MAGIC.to_vec();
+        data.extend_from_slice(&BUNDLE_VERSION.to_le_bytes());
+        data.extend(seal(&plaintext, passphrase)?);
+        write_atomically(path.as_ref(), &data)
+    }
+
+    /// Reads a bundle written by [`Bundle::write`].
+    pub fn read<P: AsRef<Path>>(path: P, passphrase: &str) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        let Some(rest) = bytes.strip_prefix(BUNDLE_MAGIC) else {
+            anyhow::bail!("`{}` is not an fssh export.", path.display());
+        };
+        let version = match rest {
+            [low, high, ..] => u16::from_le_bytes([*low, *high]),
+            _ => anyhow::bail!("`{}` is truncated.", path.display()),
+        };
+        if version != BUNDLE_VERSION {
+            anyhow::bail!(
+                "`{}` was exported by another version of fssh (format {}).",
+                path.display(),
+                version
+            );
+        }
+
+        let plaintext =
+            unseal(&rest[2..], passphrase).map_err(|e| match e.downcast_ref::<EncryptError>() {
+                Some(EncryptError::WrongPassphrase) => {
+                    anyhow::anyhow!("Wrong passphrase for `{}`.", path.display())
+                }
+                Some(EncryptError::Damaged(_)) => {
+                    e.context(format!("`{}` is damaged.", path.display()))
+                }
+                _ => e,
+            })?;
+        let entries: Vec<Entry> = bincode::deserialize(&plaintext)?;
+        let entries = entries
+            .into_iter()
+            .map(|entry| match SecretKey::from_name(&entry.name) {
+                Some(key) => Ok((local(key), entry.secret, entry.stamps)),
+                None => anyhow::bail!("Invalid secret name `{}` in the export.", entry.name),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { entries })
+    }
+}
+
+/// Returns the key with the identity files under the home directory relative to it, as `~/…`.
+fn portable(key: &SecretKey) -> SecretKey {
+    match (key, dirs::home_dir()) {
+        (SecretKey::Passphrase(path), Some(home)) => match path.strip_prefix(home) {
+            Ok(rest) => SecretKey::Passphrase(PathBuf::from("~").join(rest)),
+            Err(_) => key.clone(),
+        },
+        _ => key.clone(),
+    }
+}
+
+/// Reverts [`portable`] with the home directory of this machine.
+fn local(key: SecretKey) -> SecretKey {
+    match (&key, dirs::home_dir()) {
+        (SecretKey::Passphrase(path), Some(home)) => match path.strip_prefix("~") {
+            Ok(rest) => SecretKey::Passphrase(home.join(rest)),
+            Err(_) => key,
+        },
+        _ => key,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_dir;
+
+    #[test]
+    fn keeps_the_stamps() {
+        let dir = test_dir("bundle");
+        let path = dir.join("secrets.fssh");
+        let stamps = Stamps {
+            created: 1000,
+            used: 2000,
+        };
+        let entries = vec![
+            (
+                SecretKey::Named("enable".to_string()),
+                SecretBytes::new(b"one".to_vec()),
+                Some(stamps),
+            ),
+            (
+                SecretKey::Named("other".to_string()),
+                SecretBytes::new(b"two".to_vec()),
+                None,
+            ),
+        ];
+        Bundle::new(entries.clone())
+            .write(&path, "passphrase")
+            .unwrap();
+
+        let bundle = Bundle::read(&path, "passphrase").unwrap();
+        assert_eq!(bundle.entries(), entries.as_slice());
+        assert!(Bundle::read(&path, "wrong").is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,485 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use glob::Pattern;
+use regex::Regex;
+use serde::{de::Error as _, Deserialize, Deserializer};
+
+use crate::{
+    app::{expand_tilde, CRATE_NAME},
+    keymap::{KeySequence, Keymap, Preset, SelectorAction, TerminalAction},
+    prompt::PromptPatterns,
+    script::ScriptStep,
+    sshconfig::{SshConfigItem, DEFAULT_SSH_CONFIG},
+    store::{HostKey, Stamps, StoreConfig},
+    theme::{ColorSupport, StyleConfig, Theme, ThemePreset},
+};
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// The `fssh` configuration, loaded from `config.toml` in the fssh config directory.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The SSH config files listing the hosts, in order.
+    pub ssh_configs: Vec<PathBuf>,
+    /// The directory of the key, the stored secrets and the agent socket, see [`Config::data_dir`].
+    pub data_dir: Option<PathBuf>,
+    /// Seconds of inactivity after which the agent forgets the key.
+    pub agent_timeout: u64,
+    /// Whether `sudo` prompts may be answered with the stored password, on confirmation.
+    pub sudo: bool,
+    /// Where the secrets are stored.
+    pub store: StoreConfig,
+    /// Seconds after which a stored password or passphrase is forgotten.
+    pub max_age: Option<u64>,
+    /// Seconds without login after which a stored password or passphrase is forgotten.
+    pub max_idle: Option<u64>,
+    /// The command connecting to the hosts instead of `ssh`, see [`Config::client_for`].
+    #[serde(deserialize_with = "client_command")]
+    pub client: Option<String>,
+    /// Key bindings of the selector and of the terminal view.
+    pub keys: KeyConfig,
+    /// Colors of the selector and of the terminal view.
+    pub theme: ThemeConfig,
+    /// Prompt patterns applied to every host.
+    pub prompts: PromptConfig,
+    /// Settings applied to the hosts matching a pattern.
+    pub hosts: Vec<HostConfig>,
+}
+
+/// Key bindings: a preset, and the key sequences of the actions bound differently.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyConfig {
+    /// The built-in bindings of the selector.
+    pub preset: Preset,
+    /// Replaces the bindings of selector actions, e.g. `down = ["j", "ctrl-n"]`.
+    pub selector: HashMap<SelectorAction, Vec<KeySequence>>,
+    /// Replaces the bindings of the terminal view, single chords only since the other keys go to
+    /// the client.
+    #[serde(deserialize_with = "terminal_bindings")]
+    pub terminal: HashMap<TerminalAction, Vec<KeySequence>>,
+}
+
+/// Colors: a preset, the colors supported by the terminal, and the elements styled differently.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    /// The built-in styles.
+    pub preset: ThemePreset,
+    /// The colors the terminal can show, the other ones being replaced by the closest ones.
+    pub colors: ColorSupport,
+    /// The highlighted host.
+    pub selection: StyleConfig,
+    /// The characters matching the search query.
+    pub matched: StyleConfig,
+    /// The column titles.
+    pub header: StyleConfig,
+    /// The key hints and messages at the bottom.
+    pub status: StyleConfig,
+    /// The search query.
+    pub input: StyleConfig,
+    /// The default colors of the terminal view.
+    pub terminal: StyleConfig,
+}
+
+/// Regexes recognizing prompts and login results, in addition to the built-in ones.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptConfig {
+    /// Prompts answered with the user name of the host, none by default.
+    #[serde(deserialize_with = "prompt_patterns")]
+    pub username: Vec<String>,
+    /// Prompts answered with the stored password.
+    #[serde(deserialize_with = "prompt_patterns")]
+    pub password: Vec<String>,
+    /// Key passphrase prompts, the first capture group must match the identity file path.
+    #[serde(deserialize_with = "passphrase_patterns")]
+    pub passphrase: Vec<String>,
+    /// Second-factor prompts answered with a TOTP code.
+    #[serde(deserialize_with = "prompt_patterns")]
+    pub totp: Vec<String>,
+    /// `sudo` prompts answered with the password on confirmation.
+    #[serde(deserialize_with = "prompt_patterns")]
+    pub sudo: Vec<String>,
+    /// Markers of a successful login.
+    #[serde(deserialize_with = "prompt_patterns")]
+    pub success: Vec<String>,
+    /// Markers of a rejected login.
+    #[serde(deserialize_with = "prompt_patterns")]
+    pub failure: Vec<String>,
+}
+
+/// Per host settings.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    /// A glob pattern matched against the alias or the hostname of a host.
+    #[serde(deserialize_with = "host_pattern")]
+    pub pattern: String,
+    /// Prompt patterns for the matching hosts.
+    #[serde(default)]
+    pub prompts: PromptConfig,
+    /// An expect/send script run after login, before handing the session to the user.
+    #[serde(default, deserialize_with = "script")]
+    pub script: Vec<ScriptStep>,
+    /// Overrides the global `sudo` setting for the matching hosts.
+    pub sudo: Option<bool>,
+    /// Overrides the global `max_age` setting for the passwords of the matching hosts.
+    pub max_age: Option<u64>,
+    /// Overrides the global `max_idle` setting for the passwords of the matching hosts.
+    pub max_idle: Option<u64>,
+    /// Options passed to `ssh` when connecting to the matching hosts, e.g. `["-A"]`.
+    #[serde(default)]
+    pub ssh_args: Vec<String>,
+    /// Overrides the global `client` command for the matching hosts.
+    #[serde(default, deserialize_with = "client_command")]
+    pub client: Option<String>,
+}
+
+/// How long a stored secret is kept, see [`Config::expiry_for`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Expiry {
+    /// Seconds after which the secret is forgotten.
+    pub max_age: Option<u64>,
+    /// Seconds without login after which the secret is forgotten.
+    pub max_idle: Option<u64>,
+}
+
+impl Expiry {
+    /// Returns whether a secret with the given stamps is expired at `now`.
+    pub fn is_expired(&self, stamps: &Stamps, now: u64) -> bool {
+        let older =
+            |since: u64, max: Option<u64>| max.is_some_and(|max| now.saturating_sub(since) > max);
+        older(stamps.created, self.max_age) || older(stamps.used, self.max_idle)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ssh_configs: vec![PathBuf::from(DEFAULT_SSH_CONFIG)],
+            data_dir: None,
+            agent_timeout: 15 * 60,
+            sudo: false,
+            store: StoreConfig::default(),
+            max_age: None,
+            max_idle: None,
+            client: None,
+            keys: KeyConfig::default(),
+            theme: ThemeConfig::default(),
+            prompts: PromptConfig::default(),
+            hosts: Vec::new(),
+        }
+    }
+}
+
+impl HostConfig {
+    fn matches(&self, item: &SshConfigItem) -> bool {
+        self.matches_names(&item.host, &item.hostname)
+    }
+
+    fn matches_names(&self, alias: &str, hostname: &str) -> bool {
+        Pattern::new(&self.pattern)
+            .map(|p| p.matches(alias) || p.matches(hostname))
+            .unwrap_or(false)
+    }
+}
+
+impl Config {
+    /// Returns the default location of the configuration file.
+    pub fn default_path() -> PathBuf {
+        dirs::config_dir()
+            .unwrap()
+            .join(CRATE_NAME)
+            .join(CONFIG_FILE)
+    }
+
+    /// Loads the configuration from the given file.
+    ///
+    /// If the file doesn't exist, the default configuration is returned. The patterns, scripts and
+    /// commands are checked while parsing, so that their errors point at their line in the file.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    /// Returns the key bindings of the selector: the preset with the configured overrides.
+    pub fn selector_keymap(&self) -> Keymap<SelectorAction> {
+        let mut keymap = Keymap::selector(self.keys.preset);
+        for (action, sequences) in self.keys.selector.iter() {
+            keymap.bind(*action, sequences);
+        }
+        keymap
+    }
+
+    /// Returns the key bindings of the terminal view, with the configured overrides.
+    pub fn terminal_keymap(&self) -> Keymap<TerminalAction> {
+        let mut keymap = Keymap::terminal();
+        for (action, sequences) in self.keys.terminal.iter() {
+            keymap.bind(*action, sequences);
+        }
+        keymap
+    }
+
+    /// Returns the styles of the selector and of the terminal view: the preset with the configured
+    /// changes, limited to the colors the terminal can show.
+    pub fn theme(&self) -> Theme {
+        let theme = &self.theme;
+        let preset = Theme::preset(theme.preset);
+        Theme {
+            selection: theme.selection.apply(preset.selection),
+            matched: theme.matched.apply(preset.matched),
+            header: theme.header.apply(preset.header),
+            status: theme.status.apply(preset.status),
+            input: theme.input.apply(preset.input),
+            terminal: theme.terminal.apply(preset.terminal),
+        }
+        .adapt(theme.colors)
+    }
+
+    /// Returns the directory of the key, the stored secrets and the agent socket: `data_dir` if set,
+    /// otherwise the fssh config directory.
+    pub fn data_dir(&self) -> PathBuf {
+        match &self.data_dir {
+            Some(dir) => expand_tilde(dir),
+            None => dirs::config_dir().unwrap().join(CRATE_NAME),
+        }
+    }
+
+    /// Returns the prompt patterns for the given host.
+    ///
+    /// Patterns of matching host sections take precedence over the global ones.
+    pub fn prompts_for(&self, item: &SshConfigItem) -> anyhow::Result<PromptPatterns> {
+        let mut prompts = PromptPatterns::default();
+        prompts.extend(&self.prompts)?;
+        for host in self.hosts.iter().filter(|h| h.matches(item)) {
+            prompts.extend(&host.prompts)?;
+        }
+        Ok(prompts)
+    }
+
+    /// Returns whether `sudo` prompts of the given host may be answered, the first matching host section
+    /// setting it taking precedence over the global setting.
+    pub fn sudo_for(&self, item: &SshConfigItem) -> bool {
+        self.hosts
+            .iter()
+            .filter(|h| h.matches(item))
+            .find_map(|h| h.sudo)
+            .unwrap_or(self.sudo)
+    }
+
+    /// Returns how long the password of the given host is kept, the first matching host section
+    /// setting each limit taking precedence over the global setting.
+    pub fn expiry_for(&self, host: &HostKey) -> Expiry {
+        let hosts = || {
+            self.hosts
+                .iter()
+                .filter(|h| h.matches_names(&host.alias, &host.hostname))
+        };
+        Expiry {
+            max_age: hosts().find_map(|h| h.max_age).or(self.max_age),
+            max_idle: hosts().find_map(|h| h.max_idle).or(self.max_idle),
+        }
+    }
+
+    /// Returns how long the secrets not tied to a host, e.g. key passphrases, are kept.
+    pub fn expiry(&self) -> Expiry {
+        Expiry {
+            max_age: self.max_age,
+            max_idle: self.max_idle,
+        }
+    }
+
+    /// Returns the `ssh` options of the given host, from every matching host section in order.
+    pub fn ssh_args_for(&self, item: &SshConfigItem) -> Vec<&str> {
+        self.hosts
+            .iter()
+            .filter(|h| h.matches(item))
+            .flat_map(|h| h.ssh_args.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// Returns the command connecting to the given host, from the first matching host section
+    /// setting one or the global setting, or `None` for `ssh`.
+    ///
+    /// The command is split like a shell would, then `{host}`, `{user}`, `{hostname}` and `{port}`
+    /// are replaced in each argument, e.g. `mosh {user}@{hostname} --ssh="ssh -p {port}"`.
+    pub fn client_for(&self, item: &SshConfigItem) -> anyhow::Result<Option<Vec<String>>> {
+        let client = self
+            .hosts
+            .iter()
+            .filter(|h| h.matches(item))
+            .find_map(|h| h.client.as_deref())
+            .or(self.client.as_deref());
+        let Some(client) = client else {
+            return Ok(None);
+        };
+        Ok(Some(
+            split_command(client)?
+                .iter()
+                .map(|arg| item.format(arg))
+                .collect(),
+        ))
+    }
+
+    /// Returns the login script of the given host, from the first matching host section having one.
+    pub fn script_for(&self, item: &SshConfigItem) -> &[ScriptStep] {
+        self.hosts
+            .iter()
+            .find(|h| !h.script.is_empty() && h.matches(item))
+            .map_or(&[], |h| h.script.as_slice())
+    }
+}
+
+/// Deserializes prompt patterns, checking that they compile.
+fn prompt_patterns<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let patterns = Vec::<String>::deserialize(deserializer)?;
+    for pattern in patterns.iter() {
+        Regex::new(pattern).map_err(|e| {
+            D::Error::custom(format!("Invalid prompt pattern `{}`: {}", pattern, e))
+        })?;
+    }
+    Ok(patterns)
+}
+
+/// Deserializes key passphrase prompt patterns, checking that they capture the key path.
+fn passphrase_patterns<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    let patterns = Vec::<String>::deserialize(deserializer)?;
+    for pattern in patterns.iter() {
+        let re = Regex::new(pattern).map_err(|e| {
+            D::Error::custom(format!("Invalid prompt pattern `{}`: {}", pattern, e))
+        })?;
+        if re.captures_len() < 2 {
+            return Err(D::Error::custom(format!(
+                "Passphrase pattern `{}` must capture the key path",
+                pattern
+            )));
+        }
+    }
+    Ok(patterns)
+}
+
+/// Deserializes the glob pattern of a host section, checking its syntax.
+fn host_pattern<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Pattern::new(&pattern)
+        .map_err(|e| D::Error::custom(format!("Invalid host pattern `{}`: {}", pattern, e)))?;
+    Ok(pattern)
+}
+
+/// Deserializes a login script, checking each step.
+fn script<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<ScriptStep>, D::Error> {
+    let steps = Vec::<ScriptStep>::deserialize(deserializer)?;
+    for step in steps.iter() {
+        step.validate().map_err(D::Error::custom)?;
+    }
+    Ok(steps)
+}
+
+/// Deserializes the bindings of the terminal view, checking that they are single chords.
+fn terminal_bindings<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<TerminalAction, Vec<KeySequence>>, D::Error> {
+    let bindings = HashMap::<TerminalAction, Vec<KeySequence>>::deserialize(deserializer)?;
+    if let Some(keys) = bindings
+        .values()
+        .flatten()
+        .find(|keys| keys.chords().len() > 1)
+    {
+        return Err(D::Error::custom(format!(
+            "`{}`: the terminal view only binds single chords",
+            keys
+        )));
+    }
+    Ok(bindings)
+}
+
+/// Deserializes a client command, checking that it can be split into arguments.
+fn client_command<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let command = Option::<String>::deserialize(deserializer)?;
+    if let Some(command) = command.as_deref() {
+        split_command(command).map_err(D::Error::custom)?;
+    }
+    Ok(command)
+}
+
+/// Splits a command into arguments at whitespace, keeping quoted strings together and honoring
+/// backslash escapes outside single quotes.
+pub(crate) fn split_command(command: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => arg.get_or_insert_with(String::new).push(c),
+            (_, '\\') => {
+                let escaped = chars
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("Trailing backslash in `{}`.", command))?;
+                arg.get_or_insert_with(String::new).push(escaped);
+            }
+            (Some(_), c) => arg.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (None, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        anyhow::bail!("Unterminated quote in `{}`.", command);
+    }
+    args.extend(arg);
+    if args.is_empty() {
+        anyhow::bail!("The client command is empty.");
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_commands() {
+        for (command, args) in [
+            ("ssh -p 22", vec!["ssh", "-p", "22"]),
+            ("  mosh\t{host} ", vec!["mosh", "{host}"]),
+            (
+                r#"mosh --ssh="ssh -p 22" {host}"#,
+                vec!["mosh", "--ssh=ssh -p 22", "{host}"],
+            ),
+            (
+                r#"echo 'a "b"' "c 'd'" e\ f"#,
+                vec!["echo", r#"a "b""#, "c 'd'", "e f"],
+            ),
+            (r#"echo "a\"b" 'a\b'"#, vec!["echo", r#"a"b"#, r"a\b"]),
+            ("echo '' x", vec!["echo", "", "x"]),
+        ] {
+            assert_eq!(split_command(command).unwrap(), args, "{}", command);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        for command in ["", "  ", "ssh 'host", r#"ssh "host"#, r"ssh \"] {
+            assert!(split_command(command).is_err(), "{}", command);
+        }
+    }
+}
@@ -1,55 +1,177 @@
 use serde::{Deserialize, Serialize};
+
+use crate::encrypt::write_atomically;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Debug,
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
     hash::Hash,
-    io::Write,
-    ops::{Deref, DerefMut},
+    ops::Deref,
     path::{Path, PathBuf},
 };
 
+/// Magic bytes starting the database files, followed by the format version.
+const MAGIC: &[u8] = b"FSSHDB";
+/// The version of the format written by [`Db::flush`].
+///
+/// Files without header are version 0, a bare bincode `HashMap`.
+pub const DB_VERSION: u16 = 1;
+
 /// A simple key-value store that serializes to disk.
+///
+/// Several processes may use the same file: [`Db::flush`] only writes the keys changed through
+/// this instance, merged with the current content of the file.
 #[derive(Debug)]
 pub struct Db<K, V> {
     path: PathBuf,
     db: HashMap<K, V>,
+    changed: HashSet<K>,
 }
 
 impl<K, V> Db<K, V>
 where
-    K: Hash + Eq + Serialize + for<'de> Deserialize<'de> + Debug,
+    K: Hash + Eq + Clone + Serialize + for<'de> Deserialize<'de> + Debug,
     V: Serialize + for<'de> Deserialize<'de> + Debug,
 {
     /// Opens a database from a given file.
     ///
     /// If the file doesn't exist, an empty database is created.
-    /// If the file exists, the database is loaded from the file.
+    /// If the file exists, the database is loaded from the file. Files of an older format are
+    /// migrated as if the layout of their content didn't change, see [`Db::open_with`].
     pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::open_with(path, |_, data| Ok(bincode::deserialize(data)?))
+    }
+
+    /// Opens a database from a given file, converting the content of older formats with `migrate`.
+    ///
+    /// `migrate` gets the version of the file and its content without header. The file is rewritten
+    /// in the current format right away, and the original is kept next to it with the version as
+    /// extension, e.g. `db.v0`.
+    pub fn open_with<P, F>(path: P, migrate: F) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(u16, &[u8]) -> anyhow::Result<HashMap<K, V>>,
+    {
         let path = PathBuf::from(path.as_ref());
-        let db = if path.exists() {
-            let file = File::open(&path)?;
-            bincode::deserialize_from(file)?
-        } else {
-            HashMap::new()
+        let db = match fs::read(&path) {
+            Ok(bytes) => {
+                let (version, data) = split_header(&bytes);
+                if version == DB_VERSION {
+                    bincode::deserialize(data)?
+                } else if version > DB_VERSION {
+                    anyhow::bail!(
+                        "`{}` was written by a newer version of fssh (format {}).",
+                        path.display(),
+                        version
+                    );
+                } else {
+                    let _lock = lock(&path)?;
+                    // another process may have migrated the file meanwhile
+                    let bytes = fs::read(&path)?;
+                    match split_header(&bytes) {
+                        (DB_VERSION, data) => bincode::deserialize(data)?,
+                        (version, data) => {
+                            let db = migrate(version, data).map_err(|e| {
+                                anyhow::anyhow!(
+                                    "Failed to migrate `{}` from format {}: {}",
+                                    path.display(),
+                                    version,
+                                    e
+                                )
+                            })?;
+                            fs::copy(&path, path.with_extension(format!("v{}", version)))?;
+                            write(&path, &db)?;
+                            db
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
         };
 
-        Ok(Self { path, db })
+        Ok(Self {
+            path,
+            db,
+            changed: HashSet::new(),
+        })
+    }
+
+    /// Reads the current content of the file, which must be in the current format.
+    fn read(path: &Path) -> anyhow::Result<HashMap<K, V>> {
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        let bytes = fs::read(path)?;
+        match split_header(&bytes) {
+            (DB_VERSION, data) => Ok(bincode::deserialize(data)?),
+            (version, _) => anyhow::bail!(
+                "`{}` was rewritten in format {} since it was opened.",
+                path.display(),
+                version
+            ),
+        }
     }
 
-    /// Flushes the database to disk.
-    pub fn flush(&self) -> anyhow::Result<()> {
-        let data = bincode::serialize(&self.db)?;
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&self.path)?;
-        file.write_all(&data)?;
+    /// Flushes the changes to disk.
+    ///
+    /// The file is locked while its current content is read, merged with the changes and written
+    /// back, so that the changes of other processes since [`Db::open`] are kept. The data is written
+    /// next to the file and renamed over it, so that the file is never half written. If writing
+    /// fails, the changes are kept for the next flush.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        if !self.is_dirty() {
+            return Ok(());
+        }
+
+        let _lock = lock(&self.path)?;
+        let mut db = Self::read(&self.path)?;
+        {
+            let merged: HashMap<&K, &V> = db
+                .iter()
+                .filter(|(key, _)| !self.changed.contains(*key))
+                .chain(
+                    self.changed
+                        .iter()
+                        .filter_map(|key| self.db.get_key_value(key)),
+                )
+                .collect();
+            write(&self.path, &merged)?;
+        }
+        for key in self.changed.drain() {
+            match self.db.remove(&key) {
+                Some(value) => db.insert(key, value),
+                None => db.remove(&key),
+            };
+        }
+
+        self.db = db;
         Ok(())
     }
 }
 
+impl<K: Hash + Eq + Clone, V> Db<K, V> {
+    /// Inserts a value, returning the previous one.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.changed.insert(key.clone());
+        self.db.insert(key, value)
+    }
+
+    /// Removes a value, returning it.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let value = self.db.remove(key);
+        if value.is_some() {
+            self.changed.insert(key.clone());
+        }
+        value
+    }
+
+    /// Returns whether some changes are not flushed yet.
+    pub fn is_dirty(&self) -> bool {
+        !self.changed.is_empty()
+    }
+}
+
 impl<K, V> Deref for Db<K, V> {
     type Target = HashMap<K, V>;
 
@@ -58,8 +180,182 @@ impl<K, V> Deref for Db<K, V> {
     }
 }
 
-impl<K, V> DerefMut for Db<K, V> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.db
+/// Returns the format version of a database file, or `None` if it doesn't exist.
+pub(crate) fn format_version(path: &Path) -> anyhow::Result<Option<u16>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(split_header(&bytes).0)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the format version of the file content and the data following the header.
+fn split_header(bytes: &[u8]) -> (u16, &[u8]) {
+    match bytes.strip_prefix(MAGIC) {
+        Some(rest) if rest.len() >= 2 => (u16::from_le_bytes([rest[0], rest[1]]), &rest[2..]),
+        _ => (0, bytes),
+    }
+}
+
+/// Takes an advisory lock on the database file, released when the returned file is dropped.
+///
+/// The lock is taken on a separate file, the database file itself is replaced when written.
+fn lock(path: &Path) -> anyhow::Result<File> {
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.with_extension("lock"))?;
+    lock.lock()?;
+    Ok(lock)
+}
+
+/// Writes the content in the current format next to the file and renames it over the file, only
+/// readable by the user.
+fn write<T: Serialize>(path: &Path, db: &T) -> anyhow::Result<()> {
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&DB_VERSION.to_le_bytes());
+    data.extend(bincode::serialize(db)?);
+    write_atomically(path, &data)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Returns an empty directory for a test, removed beforehand if a previous run left it.
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fssh-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Returns `data` with the header of the given format version.
+    fn with_header(version: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&version.to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn migrates_older_format_and_keeps_backup() {
+        let dir = test_dir("db-migrate");
+        let path = dir.join("db");
+        let v0: HashMap<String, Vec<u8>> =
+            HashMap::from([("a".to_string(), vec![1, 2]), ("b".to_string(), vec![3])]);
+        let original = bincode::serialize(&v0).unwrap();
+        fs::write(&path, &original).unwrap();
+
+        let db: Db<String, (Vec<u8>, u8)> = Db::open_with(&path, |version, data| {
+            assert_eq!(version, 0);
+            let old: HashMap<String, Vec<u8>> = bincode::deserialize(data)?;
+            Ok(old.into_iter().map(|(k, v)| (k, (v, 7))).collect())
+        })
+        .unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db["a"], (vec![1, 2], 7));
+        assert_eq!(db["b"], (vec![3], 7));
+
+        // the original is kept as is, and the file is rewritten in the current format
+        assert_eq!(fs::read(dir.join("db.v0")).unwrap(), original);
+        assert_eq!(format_version(&path).unwrap(), Some(DB_VERSION));
+        let reopened: Db<String, (Vec<u8>, u8)> =
+            Db::open_with(&path, |_, _| panic!("already migrated")).unwrap();
+        assert_eq!(*reopened, *db);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migrates_headerless_format() {
+        let dir = test_dir("db-v0");
+        let path = dir.join("db");
+        let v0: HashMap<String, Vec<u8>> = HashMap::from([("a".to_string(), vec![1])]);
+        fs::write(&path, bincode::serialize(&v0).unwrap()).unwrap();
+
+        let db: Db<String, Vec<u8>> = Db::open(&path).unwrap();
+        assert_eq!(*db, v0);
+        assert!(dir.join("db.v0").exists());
+        assert_eq!(format_version(&path).unwrap(), Some(DB_VERSION));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_migration_keeps_the_file() {
+        let dir = test_dir("db-failed");
+        let path = dir.join("db");
+        let original = b"garbage".to_vec();
+        fs::write(&path, &original).unwrap();
+
+        let result: anyhow::Result<Db<String, Vec<u8>>> =
+            Db::open_with(&path, |_, _| anyhow::bail!("bad data"));
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), original);
+        assert!(!dir.join("db.v0").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_newer_format() {
+        let dir = test_dir("db-newer");
+        let path = dir.join("db");
+        fs::write(&path, with_header(DB_VERSION + 1, b"")).unwrap();
+
+        let result: anyhow::Result<Db<String, Vec<u8>>> = Db::open(&path);
+        assert!(result.is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn flush_merges_the_changed_keys_only() {
+        let dir = test_dir("db-merge");
+        let path = dir.join("db");
+        let mut first: Db<String, u8> = Db::open(&path).unwrap();
+        first.insert("kept".to_string(), 1);
+        first.insert("removed".to_string(), 2);
+        first.flush().unwrap();
+
+        // another process opened the file meanwhile
+        let mut second: Db<String, u8> = Db::open(&path).unwrap();
+        first.insert("first".to_string(), 3);
+        first.remove(&"removed".to_string());
+        first.flush().unwrap();
+        second.insert("second".to_string(), 4);
+        second.insert("kept".to_string(), 5);
+        second.flush().unwrap();
+
+        let db: Db<String, u8> = Db::open(&path).unwrap();
+        let expected = HashMap::from([
+            ("kept".to_string(), 5),
+            ("first".to_string(), 3),
+            ("second".to_string(), 4),
+        ]);
+        assert_eq!(*db, expected);
+        assert!(!first.is_dirty() && !second.is_dirty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_flush_keeps_the_changes() {
+        let dir = test_dir("db-failed-flush");
+        let path = dir.join("db");
+        let mut db: Db<String, u8> = Db::open(&path).unwrap();
+        db.insert("removed".to_string(), 1);
+        db.flush().unwrap();
+
+        db.insert("added".to_string(), 2);
+        db.remove(&"removed".to_string());
+        // the temporary file can't be created
+        fs::create_dir(dir.join("db.tmp")).unwrap();
+        assert!(db.flush().is_err());
+        assert!(db.is_dirty());
+        assert_eq!(*db, HashMap::from([("added".to_string(), 2)]));
+
+        fs::remove_dir(dir.join("db.tmp")).unwrap();
+        db.flush().unwrap();
+        let reopened: Db<String, u8> = Db::open(&path).unwrap();
+        assert_eq!(*reopened, HashMap::from([("added".to_string(), 2)]));
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,228 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+#[cfg(unix)]
+use crate::{agent::AgentClient, app::AGENT_SOCKET};
+use crate::{
+    app::{expand_tilde, KEY_FILE, NEW_KEY_FILE},
+    config::{split_command, Config},
+    db::{format_version, DB_VERSION},
+    encrypt::EncryptionManager,
+    sshconfig::read_ssh_configs,
+    store::{StoreConfig, STORE_FILES},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    Ok,
+    Warning,
+    Failure,
+}
+
+/// Prints the result of each check and keeps the worst one.
+struct Report {
+    worst: Status,
+}
+
+impl Report {
+    fn check(&mut self, status: Status, message: impl Display) {
+        let label = match status {
+            Status::Ok => "ok",
+            Status::Warning => "warn",
+            Status::Failure => "FAIL",
+        };
+        println!("{:<4}  {}", label, message);
+        self.worst = self.worst.max(status);
+    }
+}
+
+/// Checks the SSH config, the configuration, the key and store files and the programs run by fssh,
+/// printing a line per check.
+///
+/// Returns whether no check failed, warnings being allowed.
+pub fn doctor() -> anyhow::Result<bool> {
+    let mut report = Report { worst: Status::Ok };
+
+    let path = Config::default_path();
+    let config = match Config::load(&path) {
+        Ok(config) if path.exists() => {
+            report.check(Status::Ok, format!("Configuration: `{}`.", path.display()));
+            config
+        }
+        Ok(config) => {
+            report.check(Status::Ok, "Configuration: none, using the defaults.");
+            config
+        }
+        Err(e) => {
+            report.check(Status::Failure, format!("Configuration: {}", e));
+            Config::default()
+        }
+    };
+
+    match read_ssh_configs(&config.ssh_configs) {
+        Ok(hosts) if hosts.is_empty() => report.check(
+            Status::Warning,
+            "SSH config: no host with a `HostName` to connect to.",
+        ),
+        Ok(hosts) => report.check(Status::Ok, format!("SSH config: {} host(s).", hosts.len())),
+        Err(e) => report.check(Status::Failure, format!("SSH config: {}", e)),
+    }
+
+    let dir = config.data_dir();
+    check_program(&mut report, "ssh");
+    // the programs configured to connect instead of `ssh`
+    let mut clients: Vec<String> = config
+        .hosts
+        .iter()
+        .filter_map(|host| host.client.as_deref())
+        .chain(config.client.as_deref())
+        .filter_map(|client| split_command(client).ok()?.into_iter().next())
+        .filter(|program| program != "ssh")
+        .collect();
+    clients.sort_unstable();
+    clients.dedup();
+    for program in clients {
+        check_program(&mut report, &program);
+    }
+    match &config.store {
+        StoreConfig::File => check_key(&mut report, &dir)?,
+        StoreConfig::Pass { .. } => check_program(&mut report, "pass"),
+        StoreConfig::Age { identity, .. } => {
+            check_program(&mut report, "age");
+            let identity = expand_tilde(identity);
+            if identity.exists() {
+                report.check(
+                    Status::Ok,
+                    format!("Age identity: `{}`.", identity.display()),
+                );
+            } else {
+                report.check(
+                    Status::Failure,
+                    format!("Age identity: `{}` doesn't exist.", identity.display()),
+                );
+            }
+        }
+    }
+    // the files of the default store are left behind when another store is configured
+    for file in STORE_FILES {
+        check_store_file(&mut report, &dir.join(file))?;
+    }
+
+    #[cfg(unix)]
+    if AgentClient::connect(&dir.join(AGENT_SOCKET)).is_some() {
+        report.check(Status::Ok, "Agent: running.");
+    } else {
+        report.check(Status::Ok, "Agent: not running.");
+    }
+
+    Ok(report.worst < Status::Failure)
+}
+
+/// Returns the path of `program` in the `PATH`, or of `program` itself when it is a path.
+fn find_program(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        return Some(expand_tilde(Path::new(program))).filter(|path| path.is_file());
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+fn check_program(report: &mut Report, program: &str) {
+    match find_program(program) {
+        Some(path) => report.check(Status::Ok, format!("`{}`: `{}`.", program, path.display())),
+        None => report.check(
+            Status::Failure,
+            format!("`{}`: not found in the PATH.", program),
+        ),
+    }
+}
+
+/// Returns the permission bits of the file readable or writable by other users, if any.
+#[cfg(unix)]
+fn shared_mode(path: &Path) -> anyhow::Result<Option<u32>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)?.permissions().mode() & 0o777;
+    Ok(Some(mode).filter(|mode| mode & 0o077 != 0))
+}
+
+#[cfg(not(unix))]
+fn shared_mode(_path: &Path) -> anyhow::Result<Option<u32>> {
+    Ok(None)
+}
+
+fn check_key(report: &mut Report, dir: &Path) -> anyhow::Result<()> {
+    let path = dir.join(KEY_FILE);
+    let stored = STORE_FILES.iter().any(|file| dir.join(file).exists());
+    if !path.exists() && stored {
+        report.check(
+            Status::Failure,
+            format!(
+                "Key: `{}` is missing but secrets are stored, restore it from a backup.",
+                path.display()
+            ),
+        );
+    } else if !path.exists() {
+        report.check(
+            Status::Ok,
+            "Key: not created yet, it is generated when a secret is first stored.",
+        );
+    } else if let Some(mode) = shared_mode(&path)? {
+        report.check(
+            Status::Failure,
+            format!(
+                "Key: `{}` is accessible by other users (mode {:o}), run `chmod 600 {}`.",
+                path.display(),
+                mode,
+                path.display()
+            ),
+        );
+    } else if EncryptionManager::is_protected(&path) {
+        report.check(Status::Ok, "Key: protected by a master passphrase.");
+    } else {
+        report.check(Status::Ok, "Key: not protected by a master passphrase.");
+    }
+
+    if dir.join(NEW_KEY_FILE).exists() {
+        report.check(
+            Status::Warning,
+            "Key: a key rotation was interrupted, run `fssh rotate-key` to finish it.",
+        );
+    }
+    Ok(())
+}
+
+fn check_store_file(report: &mut Report, path: &Path) -> anyhow::Result<()> {
+    let Some(version) = format_version(path)? else {
+        return Ok(());
+    };
+    let name = path.display();
+    if version > DB_VERSION {
+        report.check(
+            Status::Failure,
+            format!(
+                "`{}` was written by a newer version of fssh (format {}).",
+                name, version
+            ),
+        );
+    } else if let Some(mode) = shared_mode(path)? {
+        report.check(
+            Status::Warning,
+            format!(
+                "`{}` is readable by other users (mode {:o}), its secrets are encrypted but run `chmod 600 {}`.",
+                name, mode, name
+            ),
+        );
+    } else if version < DB_VERSION {
+        report.check(
+            Status::Ok,
+            format!("`{}`: format {}, migrated on next use.", name, version),
+        );
+    } else {
+        report.check(Status::Ok, format!("`{}`.", name));
+    }
+    Ok(())
+}
@@ -1,8 +1,201 @@
 use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
     Aes256Gcm, Key,
 };
-use std::{fs, path::Path};
+use argon2::{Algorithm, Argon2, Params, Version};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use zeroize::Zeroize;
+
+use crate::secret::SecretBytes;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Magic bytes of a key file wrapped with a master passphrase.
+const WRAPPED_MAGIC: &[u8] = b"FSSHKEY\x01";
+const SALT_LEN: usize = 16;
+/// Magic bytes and version of the ciphertexts prefixed with the id of their key.
+const CIPHERTEXT_MAGIC: &[u8] = b"FS\x01";
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// The largest key derivation costs accepted from a file, far above the defaults, so that a
+/// damaged file can't make the derivation exhaust the memory or run for hours.
+const MAX_M_COST: u32 = 1 << 20;
+const MAX_T_COST: u32 = 64;
+const MAX_P_COST: u32 = 16;
+
+/// Identifies the key a ciphertext was encrypted with, without revealing it.
+pub type KeyId = [u8; KEY_ID_LEN];
+
+/// The errors of [`EncryptionManager`], wrapped in [`anyhow::Error`].
+#[derive(thiserror::Error, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum EncryptError {
+    /// The key file doesn't hold a 32 bytes key.
+    #[error("The key must be 32 bytes long, found {0} bytes. Restore the key file from a backup.")]
+    KeyLength(usize),
+    /// The data is too short to hold a nonce and an authentication tag.
+    #[error("The encrypted data is truncated ({0} bytes).")]
+    ShortCiphertext(usize),
+    /// The data was encrypted with another key, according to its key id.
+    #[error("The data was encrypted with another key (id {found}, the current key is {current}).")]
+    OtherKey {
+        /// The id of the key the data was encrypted with.
+        found: String,
+        /// The id of the current key.
+        current: String,
+    },
+    /// The data was altered, or encrypted with another key without key id.
+    #[error("The data can't be authenticated with the current key.")]
+    Authentication,
+    /// The master passphrase doesn't unwrap the key.
+    #[error("Wrong master passphrase.")]
+    WrongPassphrase,
+    /// The data protected by a passphrase is truncated or altered, e.g. a damaged key or bundle.
+    #[error("The data protected by a passphrase is damaged: {0}.")]
+    Damaged(String),
+}
+
+/// Data encrypted with a key derived from a passphrase (Argon2id), e.g. a data key wrapped with
+/// the master passphrase.
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl Sealed {
+    fn seal(data: &[u8], passphrase: &str, params: Params) -> anyhow::Result<Self> {
+        let mut salt = vec![0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+
+        let mut kek = derive_key(passphrase, &salt, &params)?;
+        let cipher = Aes256Gcm::new(&kek);
+        kek.zeroize();
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, data)
+            .map_err(|e| anyhow::anyhow!(e))?;
+
+        Ok(Self {
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+            salt,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    fn open(&self, passphrase: &str) -> anyhow::Result<SecretBytes> {
+        if self.nonce.len() != NONCE_LEN {
+            let reason = format!("the nonce is {} bytes long", self.nonce.len());
+            return Err(EncryptError::Damaged(reason).into());
+        }
+        if self.m_cost > MAX_M_COST || self.t_cost > MAX_T_COST || self.p_cost > MAX_P_COST {
+            let reason = format!(
+                "the key derivation costs {}, {} and {} are too high",
+                self.m_cost, self.t_cost, self.p_cost
+            );
+            return Err(EncryptError::Damaged(reason).into());
+        }
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, None).map_err(|e| {
+            EncryptError::Damaged(format!("invalid key derivation parameters ({})", e))
+        })?;
+        let mut kek = derive_key(passphrase, &self.salt, &params)
+            .map_err(|e| EncryptError::Damaged(e.to_string()))?;
+        let cipher = Aes256Gcm::new(&kek);
+        kek.zeroize();
+        cipher
+            .decrypt(self.nonce.as_slice().into(), self.ciphertext.as_slice())
+            .map(SecretBytes::new)
+            .map_err(|_| EncryptError::WrongPassphrase.into())
+    }
+}
+
+/// Encrypts `data` with a key derived from `passphrase`, independent of the fssh key.
+pub fn seal(data: &[u8], passphrase: &str) -> anyhow::Result<Vec<u8>> {
+    let sealed = Sealed::seal(data, passphrase, Params::default())?;
+    Ok(bincode::serialize(&sealed)?)
+}
+
+/// Decrypts data returned by [`seal`], failing with [`EncryptError::WrongPassphrase`] if the
+/// passphrase differs, and with [`EncryptError::Damaged`] if the data can't be read.
+pub fn unseal(data: &[u8], passphrase: &str) -> anyhow::Result<SecretBytes> {
+    let sealed: Sealed = bincode::deserialize(data)
+        .map_err(|_| EncryptError::Damaged(format!("{} bytes can't be read", data.len())))?;
+    sealed.open(passphrase)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: &Params) -> anyhow::Result<Key<Aes256Gcm>> {
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone());
+    let mut key = Key::<Aes256Gcm>::default();
+    argon2
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive the key: {}", e))?;
+    Ok(key)
+}
+
+fn key_from_slice(bytes: &[u8]) -> anyhow::Result<Key<Aes256Gcm>> {
+    if bytes.len() != 32 {
+        return Err(EncryptError::KeyLength(bytes.len()).into());
+    }
+    Ok(*Key::<Aes256Gcm>::from_slice(bytes))
+}
+
+/// Reads a key file, restricting it to the user if other users can access it.
+fn read_key_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let bytes = fs::read(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// Creates a file only readable and writable by the user.
+fn create_private(path: &Path) -> anyhow::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    Ok(options.open(path)?)
+}
+
+/// Writes `data` to a temporary file next to `path` and renames it, so that the key is never half written.
+///
+/// The temporary file is named after the whole file name, so that `key` and `key.new` don't share one.
+pub(crate) fn write_atomically(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = create_private(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Encrypts and decrypts the stored secrets.
+pub trait Cipher {
+    /// Encrypts the given data.
+    fn encrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>>;
+    /// Decrypts data returned by [`Cipher::encrypt`].
+    fn decrypt(&self, data: &[u8]) -> anyhow::Result<SecretBytes>;
+}
 
 /// An encryption manager that uses AES-256-GCM to encrypt and decrypt data.
 pub struct EncryptionManager {
@@ -12,25 +205,127 @@ pub struct EncryptionManager {
 impl EncryptionManager {
     /// Creates a new [`EncryptionManager`] instance using the encryption key stored in the given file.
     ///
-    /// If the file does not exist, a new key is created.
-    /// If the file exists, the key is loaded from the file.
+    /// If the file does not exist, a new key is created, only readable by the user.
+    /// If the file exists, the key is loaded from the file, which is restricted to the user if other
+    /// users can access it.
+    /// Keys protected by a master passphrase must be opened with [`EncryptionManager::unlock`].
     pub fn new<P: AsRef<Path>>(key_path: P) -> anyhow::Result<Self> {
-        let key = match fs::read(key_path.as_ref()) {
-            Ok(bytes) => Key::<Aes256Gcm>::from_slice(&bytes).clone(),
-            Err(_) => {
+        let key = match read_key_file(key_path.as_ref()) {
+            Ok(bytes) if bytes.starts_with(WRAPPED_MAGIC) => {
+                anyhow::bail!("The key is protected by a master passphrase.")
+            }
+            Ok(bytes) => key_from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
                 let key = Aes256Gcm::generate_key(OsRng);
-                fs::write(key_path, key.as_slice())?;
+                write_atomically(key_path.as_ref(), key.as_slice())?;
                 key
             }
+            Err(e) => return Err(e.into()),
         };
 
         Ok(EncryptionManager { key })
     }
 
+    /// Creates a [`EncryptionManager`] instance with a newly generated key, not stored anywhere.
+    pub fn generate() -> Self {
+        EncryptionManager {
+            key: Aes256Gcm::generate_key(OsRng),
+        }
+    }
+
+    /// Returns the id of the key, the first bytes of its SHA-1 digest.
+    pub fn key_id(&self) -> KeyId {
+        let digest = Sha1::digest(self.key.as_slice());
+        let mut id = KeyId::default();
+        id.copy_from_slice(&digest[..KEY_ID_LEN]);
+        id
+    }
+
+    /// Returns the id of the key the given data was encrypted with.
+    ///
+    /// Data encrypted before key ids were introduced has none, but may rarely look like it has one.
+    pub fn key_id_of(data: &[u8]) -> Option<KeyId> {
+        let id = data.strip_prefix(CIPHERTEXT_MAGIC)?.get(..KEY_ID_LEN)?;
+        let mut key_id = KeyId::default();
+        key_id.copy_from_slice(id);
+        Some(key_id)
+    }
+
+    /// Returns whether the key stored in the given file is protected by a master passphrase.
+    pub fn is_protected<P: AsRef<Path>>(key_path: P) -> bool {
+        fs::read(key_path)
+            .map(|bytes| bytes.starts_with(WRAPPED_MAGIC))
+            .unwrap_or(false)
+    }
+
+    /// Creates a new [`EncryptionManager`] instance from a key protected by a master passphrase.
+    ///
+    /// Unprotected keys are loaded as in [`EncryptionManager::new`].
+    pub fn unlock<P: AsRef<Path>>(key_path: P, passphrase: &str) -> anyhow::Result<Self> {
+        let bytes = read_key_file(key_path.as_ref())?;
+        match bytes.strip_prefix(WRAPPED_MAGIC) {
+            Some(wrapped) => {
+                let key = unseal(wrapped, passphrase).map_err(|e| {
+                    match e.downcast_ref::<EncryptError>() {
+                        Some(EncryptError::Damaged(_)) => e.context(format!(
+                            "The key `{}` is damaged, restore it from a backup.",
+                            key_path.as_ref().display()
+                        )),
+                        _ => e,
+                    }
+                })?;
+                Ok(EncryptionManager {
+                    key: key_from_slice(&key)?,
+                })
+            }
+            None => Self::new(key_path),
+        }
+    }
+
+    /// Protects the key with a master passphrase, or changes the passphrase of a protected key.
+    pub fn protect<P: AsRef<Path>>(&self, key_path: P, passphrase: &str) -> anyhow::Result<()> {
+        let mut data = WRAPPED_MAGIC.to_vec();
+        data.extend(seal(self.key.as_slice(), passphrase)?);
+        write_atomically(key_path.as_ref(), &data)
+    }
+
+    /// Stores the key unprotected, removing the master passphrase.
+    pub fn unprotect<P: AsRef<Path>>(&self, key_path: P) -> anyhow::Result<()> {
+        write_atomically(key_path.as_ref(), self.key.as_slice())
+    }
+
+    /// Creates a [`EncryptionManager`] instance from the raw key bytes.
+    pub(crate) fn from_raw(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(EncryptionManager {
+            key: key_from_slice(bytes)?,
+        })
+    }
+
+    /// Returns the raw key bytes.
+    pub(crate) fn raw(&self) -> &[u8] {
+        self.key.as_slice()
+    }
+
+    /// Keeps the key out of swap.
+    ///
+    /// The manager must not be moved afterwards, e.g. by keeping it boxed.
+    #[cfg(unix)]
+    pub(crate) fn lock_in_memory(&self) -> anyhow::Result<()> {
+        // SAFETY: the pointer and the length describe the key owned by `self`
+        let ret = unsafe { libc::mlock(self.key.as_ptr().cast(), self.key.len()) };
+        if ret != 0 {
+            anyhow::bail!(
+                "Failed to lock the key in memory: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+
     /// Encrypts the given data and returns the ciphertext along with the nonce.
     ///
     /// The nonce is generated for each message to ensure uniqueness. The returned
-    /// vector contains the nonce followed by the ciphertext.
+    /// vector contains a versioned key id, the nonce and the ciphertext.
     pub fn encrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
         let nonce = Aes256Gcm::generate_nonce(&mut OsRng); // 96-bits; unique per message
         let cipher = Aes256Gcm::new(&self.key);
@@ -39,6 +334,8 @@ impl EncryptionManager {
             .map_err(|e| anyhow::anyhow!(e))?;
 
         let mut encrypted = Vec::new();
+        encrypted.extend_from_slice(CIPHERTEXT_MAGIC);
+        encrypted.extend_from_slice(&self.key_id());
         encrypted.extend_from_slice(&nonce);
         encrypted.extend_from_slice(&ciphertext);
         Ok(encrypted)
@@ -46,13 +343,182 @@ impl EncryptionManager {
 
     /// Decrypts the given encrypted text, which consists of a nonce and ciphertext, and returns the original message.
     ///
-    /// The input data is expected to contain the nonce as the first 12 bytes, followed by the ciphertext.
-    pub fn decrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
-        let nonce = &data[..12];
-        let ciphertext = &data[12..];
+    /// The input data is expected to contain the versioned key id, then the nonce as the next 12 bytes,
+    /// followed by the ciphertext. Data without a key id is decrypted as the nonce and the ciphertext.
+    pub fn decrypt(&self, data: &[u8]) -> anyhow::Result<SecretBytes> {
+        if let Some(key_id) = Self::key_id_of(data) {
+            let prefixed = &data[CIPHERTEXT_MAGIC.len() + KEY_ID_LEN..];
+            if key_id == self.key_id() {
+                return self.decrypt_payload(prefixed);
+            }
+            // a nonce without key id may start like one
+            return self.decrypt_payload(data).map_err(|_| {
+                EncryptError::OtherKey {
+                    found: hex(&key_id),
+                    current: hex(&self.key_id()),
+                }
+                .into()
+            });
+        }
+        self.decrypt_payload(data)
+    }
+
+    /// Decrypts a nonce followed by the ciphertext.
+    fn decrypt_payload(&self, data: &[u8]) -> anyhow::Result<SecretBytes> {
+        if data.len() < NONCE_LEN + TAG_LEN {
+            return Err(EncryptError::ShortCiphertext(data.len()).into());
+        }
+        let nonce = &data[..NONCE_LEN];
+        let ciphertext = &data[NONCE_LEN..];
         let cipher = Aes256Gcm::new(&self.key);
         cipher
             .decrypt(nonce.into(), ciphertext)
-            .map_err(|e| anyhow::anyhow!(e))
+            .map(SecretBytes::new)
+            .map_err(|_| EncryptError::Authentication.into())
+    }
+}
+
+/// Formats a key id for messages.
+pub(crate) fn hex(key_id: &KeyId) -> String {
+    key_id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Cipher for EncryptionManager {
+    fn encrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        EncryptionManager::encrypt(self, data)
+    }
+
+    fn decrypt(&self, data: &[u8]) -> anyhow::Result<SecretBytes> {
+        EncryptionManager::decrypt(self, data)
+    }
+}
+
+impl Drop for EncryptionManager {
+    fn drop(&mut self) {
+        self.key.as_mut_slice().zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_of(result: anyhow::Result<SecretBytes>) -> EncryptError {
+        result
+            .err()
+            .and_then(|e| e.downcast_ref::<EncryptError>().cloned())
+            .expect("an encryption error")
+    }
+
+    /// Cheap costs, the defaults being slow in debug builds.
+    fn sealed(data: &[u8], passphrase: &str) -> Sealed {
+        let params = Params::new(Params::MIN_M_COST, 1, 1, None).unwrap();
+        Sealed::seal(data, passphrase, params).unwrap()
+    }
+
+    #[test]
+    fn unseals_with_the_passphrase_only() {
+        let data = bincode::serialize(&sealed(b"key", "right")).unwrap();
+        assert_eq!(unseal(&data, "right").unwrap().as_slice(), b"key");
+        assert_eq!(
+            error_of(unseal(&data, "wrong")),
+            EncryptError::WrongPassphrase
+        );
+    }
+
+    #[test]
+    fn rejects_short_data() {
+        let data = bincode::serialize(&sealed(b"key", "right")).unwrap();
+        for len in [0, 4, data.len() / 2, data.len() - 1] {
+            assert!(
+                matches!(
+                    error_of(unseal(&data[..len], "right")),
+                    EncryptError::Damaged(_)
+                ),
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_data() {
+        let mut short_nonce = sealed(b"key", "right");
+        short_nonce.nonce.truncate(4);
+        let mut costly = sealed(b"key", "right");
+        costly.m_cost = u32::MAX;
+        let mut invalid = sealed(b"key", "right");
+        invalid.t_cost = 0;
+        let mut short_salt = sealed(b"key", "right");
+        short_salt.salt.truncate(2);
+        for sealed in [short_nonce, costly, invalid, short_salt] {
+            let data = bincode::serialize(&sealed).unwrap();
+            assert!(matches!(
+                error_of(unseal(&data, "right")),
+                EncryptError::Damaged(_)
+            ));
+        }
+
+        // altered bytes can't be told apart from another passphrase
+        let mut altered = sealed(b"key", "right");
+        altered.ciphertext[0] ^= 1;
+        let data = bincode::serialize(&altered).unwrap();
+        assert_eq!(
+            error_of(unseal(&data, "right")),
+            EncryptError::WrongPassphrase
+        );
+    }
+
+    #[test]
+    fn decrypts_with_the_key_only() {
+        let manager = EncryptionManager::generate();
+        let data = manager.encrypt(b"secret").unwrap();
+        assert_eq!(manager.decrypt(&data).unwrap().as_slice(), b"secret");
+
+        let other = EncryptionManager::generate();
+        assert!(matches!(
+            error_of(other.decrypt(&data)),
+            EncryptError::OtherKey { .. }
+        ));
+        // data without key id can't tell the key it was encrypted with
+        let legacy = &data[CIPHERTEXT_MAGIC.len() + KEY_ID_LEN..];
+        assert_eq!(
+            error_of(other.decrypt(legacy)),
+            EncryptError::Authentication
+        );
+        assert_eq!(manager.decrypt(legacy).unwrap().as_slice(), b"secret");
+
+        let mut altered = data.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            error_of(manager.decrypt(&altered)),
+            EncryptError::Authentication
+        );
+        assert_eq!(
+            error_of(manager.decrypt(&data[..20])),
+            EncryptError::ShortCiphertext(20 - CIPHERTEXT_MAGIC.len() - KEY_ID_LEN)
+        );
+    }
+
+    #[test]
+    fn rejects_keys_of_another_length() {
+        let error = EncryptionManager::from_raw(&[0; 16]).err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<EncryptError>(),
+            Some(&EncryptError::KeyLength(16))
+        );
+    }
+
+    #[test]
+    fn temporary_files_are_named_after_the_whole_file_name() {
+        let dir = crate::db::tests::test_dir("write-atomically");
+        write_atomically(&dir.join("key"), b"current").unwrap();
+        // the temporary file of `key.new` once was the one of `key`
+        fs::create_dir(dir.join("key.tmp")).unwrap();
+        write_atomically(&dir.join("key.new"), b"next").unwrap();
+        assert_eq!(fs::read(dir.join("key")).unwrap(), b"current");
+        assert_eq!(fs::read(dir.join("key.new")).unwrap(), b"next");
+        assert!(!dir.join("key.new.tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -1,6 +1,9 @@
-use crossterm::event::Event;
+use crossterm::event::{Event, KeyCode, KeyModifiers};
 use std::ops::{Deref, DerefMut};
 use unicode_width::UnicodeWidthStr;
+use zeroize::Zeroize;
+
+use crate::secret::SecretString;
 
 use tui_input::{backend::crossterm::EventHandler, Input};
 
@@ -35,6 +38,11 @@ impl InputBuffer {
         self.prompt.clone() + self.input.value()
     }
 
+    /// Replaces the input, with the cursor at its end.
+    pub(crate) fn set_value(&mut self, value: &str) {
+        self.input = Input::new(value.to_string());
+    }
+
     pub(crate) fn visual_cursor(&self) -> usize {
         UnicodeWidthStr::width(self.prompt.as_str()) + self.input.visual_cursor()
     }
@@ -43,3 +51,92 @@ impl InputBuffer {
         self.input.handle_event(&event);
     }
 }
+
+/// The initial capacity of a [`SecretInput`], enough for most passwords without reallocating.
+const SECRET_CAPACITY: usize = 256;
+
+/// A single line input for secrets, edited in place in a buffer wiped from memory when dropped.
+///
+/// Unlike [`Input`], editing never leaves a copy of the text behind: the buffer only grows by
+/// moving to a larger secret buffer.
+pub(crate) struct SecretInput {
+    value: SecretString,
+    // in characters
+    cursor: usize,
+}
+
+impl SecretInput {
+    pub(crate) fn new() -> Self {
+        Self {
+            value: SecretString::new(String::with_capacity(SECRET_CAPACITY)),
+            cursor: 0,
+        }
+    }
+
+    pub(crate) fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Takes the typed secret out of the input, leaving it empty.
+    pub(crate) fn take(&mut self) -> SecretString {
+        self.cursor = 0;
+        std::mem::replace(&mut self.value, SecretString::new(String::new()))
+    }
+
+    pub(crate) fn visual_cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Returns the byte offset of the character at `cursor`.
+    fn offset(&self, cursor: usize) -> usize {
+        self.value
+            .char_indices()
+            .nth(cursor)
+            .map_or(self.value.len(), |(i, _)| i)
+    }
+
+    fn insert(&mut self, c: char) {
+        if self.value.len() + c.len_utf8() > self.value.capacity() {
+            let mut larger = SecretString::new(String::with_capacity(
+                (self.value.capacity() * 2).max(SECRET_CAPACITY),
+            ));
+            larger.push_str(&self.value);
+            // the previous buffer is wiped when dropped
+            self.value = larger;
+        }
+        let offset = self.offset(self.cursor);
+        self.value.insert(offset, c);
+        self.cursor += 1;
+    }
+
+    fn remove(&mut self, cursor: usize) {
+        if cursor < self.value.chars().count() {
+            let offset = self.offset(cursor);
+            self.value.remove(offset);
+        }
+    }
+
+    pub(crate) fn handle_event(&mut self, event: Event) {
+        let Event::Key(key) = event else {
+            return;
+        };
+        let len = self.value.chars().count();
+        match (key.code, key.modifiers) {
+            (KeyCode::Char('u'), KeyModifiers::CONTROL) => {
+                self.value.zeroize();
+                self.cursor = 0;
+            }
+            (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => self.insert(c),
+            (KeyCode::Backspace, _) if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.remove(self.cursor);
+            }
+            (KeyCode::Delete, _) => self.remove(self.cursor),
+            (KeyCode::Left, _) => self.cursor = self.cursor.saturating_sub(1),
+            (KeyCode::Right, _) => self.cursor = (self.cursor + 1).min(len),
+            (KeyCode::Home, _) => self.cursor = 0,
+            (KeyCode::End, _) => self.cursor = len,
+            _ => {}
+        }
+    }
+}
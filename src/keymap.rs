@@ -0,0 +1,532 @@
+use std::{fmt, hash::Hash, str::FromStr};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::Deserialize;
+
+/// The modifiers that are part of a chord, others like `SUPER` are ignored.
+const CHORD_MODIFIERS: KeyModifiers = KeyModifiers::CONTROL
+    .union(KeyModifiers::ALT)
+    .union(KeyModifiers::SHIFT);
+
+/// A key pressed along with its modifiers, e.g. `ctrl-n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyChord {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl KeyChord {
+    /// Returns the chord of a key event.
+    ///
+    /// Shift is part of the character of character keys, so `G` and `shift-g` are the same chord.
+    pub fn from_event(key: &KeyEvent) -> Self {
+        Self::new(key.code, key.modifiers)
+    }
+
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        let mut modifiers = modifiers & CHORD_MODIFIERS;
+        let code = match code {
+            KeyCode::Char(c) if modifiers.contains(KeyModifiers::SHIFT) => {
+                modifiers.remove(KeyModifiers::SHIFT);
+                KeyCode::Char(c.to_ascii_uppercase())
+            }
+            code => code,
+        };
+        Self { code, modifiers }
+    }
+
+    /// Returns whether the chord types a character, which the search mode inserts in the query.
+    pub fn is_text(&self) -> bool {
+        matches!(self.code, KeyCode::Char(_))
+            && !self
+                .modifiers
+                .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+    }
+}
+
+impl FromStr for KeyChord {
+    type Err = anyhow::Error;
+
+    /// Parses chords like `j`, `G`, `ctrl-n`, `alt-<`, `enter` or `pagedown`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = s;
+        // the key itself may be `-`, e.g. `ctrl--`
+        while let Some((modifier, key)) = rest.split_once('-').filter(|(_, key)| !key.is_empty()) {
+            modifiers |= match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => anyhow::bail!("Unknown modifier `{}` in `{}`.", modifier, s),
+            };
+            rest = key;
+        }
+
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match rest.to_ascii_lowercase().as_str() {
+                "esc" => KeyCode::Esc,
+                "enter" => KeyCode::Enter,
+                "tab" => KeyCode::Tab,
+                "backtab" => KeyCode::BackTab,
+                "backspace" => KeyCode::Backspace,
+                "delete" | "del" => KeyCode::Delete,
+                "insert" | "ins" => KeyCode::Insert,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "pageup" | "pgup" => KeyCode::PageUp,
+                "pagedown" | "pgdn" => KeyCode::PageDown,
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "space" => KeyCode::Char(' '),
+                name => match name.strip_prefix('f').and_then(|n| n.parse().ok()) {
+                    Some(n @ 1..=12) => KeyCode::F(n),
+                    _ => anyhow::bail!("Unknown key `{}` in `{}`.", rest, s),
+                },
+            },
+        };
+        Ok(Self::new(code, modifiers))
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            write!(f, "Ctrl-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            write!(f, "Alt-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SHIFT) {
+            write!(f, "Shift-")?;
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(c) => write!(f, "{}", c),
+            KeyCode::Esc => write!(f, "Esc"),
+            KeyCode::Enter => write!(f, "Enter"),
+            KeyCode::Tab => write!(f, "Tab"),
+            KeyCode::BackTab => write!(f, "BackTab"),
+            KeyCode::Backspace => write!(f, "Backspace"),
+            KeyCode::Delete => write!(f, "Del"),
+            KeyCode::Insert => write!(f, "Ins"),
+            KeyCode::Home => write!(f, "Home"),
+            KeyCode::End => write!(f, "End"),
+            KeyCode::PageUp => write!(f, "PgUp"),
+            KeyCode::PageDown => write!(f, "PgDn"),
+            KeyCode::Up => write!(f, "↑"),
+            KeyCode::Down => write!(f, "↓"),
+            KeyCode::Left => write!(f, "←"),
+            KeyCode::Right => write!(f, "→"),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+/// Chords pressed one after the other, e.g. `g g`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeySequence(Vec<KeyChord>);
+
+impl KeySequence {
+    /// Returns the chords of the sequence.
+    pub fn chords(&self) -> &[KeyChord] {
+        &self.0
+    }
+}
+
+impl FromStr for KeySequence {
+    type Err = anyhow::Error;
+
+    /// Parses chords separated by spaces, e.g. `g g` or `ctrl-x ctrl-c`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chords = s
+            .split_whitespace()
+            .map(KeyChord::from_str)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if chords.is_empty() {
+            anyhow::bail!("Empty key binding.");
+        }
+        Ok(Self(chords))
+    }
+}
+
+impl TryFrom<String> for KeySequence {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for KeySequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // `gg` reads better than `g g`
+        let separator = if self.0.iter().all(KeyChord::is_text) {
+            ""
+        } else {
+            " "
+        };
+        for (i, chord) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "{}", separator)?;
+            }
+            write!(f, "{}", chord)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a key binding does in a view.
+pub trait Action: Copy + Eq + Hash + 'static {
+    /// Every action, in the order of the help.
+    const ALL: &'static [Self];
+
+    /// Returns what the action does, shown in the help.
+    fn description(self) -> &'static str;
+}
+
+/// The actions of the host selector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectorAction {
+    /// Highlight the previous host.
+    Up,
+    /// Highlight the next host.
+    Down,
+    /// Highlight the first host.
+    Top,
+    /// Highlight the last host.
+    Bottom,
+    /// Connect to the highlighted host.
+    Connect,
+    /// Start searching.
+    Search,
+    /// Leave the search, or quit.
+    Quit,
+    /// Type a new password for the highlighted host.
+    Edit,
+    /// Forget the stored password of the highlighted host.
+    Forget,
+    /// Show the stored password of the highlighted host.
+    Reveal,
+    /// Show the key bindings.
+    Help,
+}
+
+impl Action for SelectorAction {
+    const ALL: &'static [Self] = &[
+        Self::Up,
+        Self::Down,
+        Self::Top,
+        Self::Bottom,
+        Self::Connect,
+        Self::Search,
+        Self::Quit,
+        Self::Edit,
+        Self::Forget,
+        Self::Reveal,
+        Self::Help,
+    ];
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Up => "move up",
+            Self::Down => "move down",
+            Self::Top => "move to the first host",
+            Self::Bottom => "move to the last host",
+            Self::Connect => "connect",
+            Self::Search => "search",
+            Self::Quit => "quit",
+            Self::Edit => "edit password",
+            Self::Forget => "forget password",
+            Self::Reveal => "reveal password",
+            Self::Help => "help",
+        }
+    }
+}
+
+/// The commands of the terminal view, handled by fssh instead of being sent to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TerminalAction {
+    /// Answer the `sudo` prompt being offered with the stored password.
+    SendSudoPassword,
+    /// Cancel the running login script.
+    CancelScript,
+}
+
+impl Action for TerminalAction {
+    const ALL: &'static [Self] = &[Self::SendSudoPassword, Self::CancelScript];
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::SendSudoPassword => "send the stored password",
+            Self::CancelScript => "cancel the login script",
+        }
+    }
+}
+
+/// The built-in key bindings of the selector, which the configuration can override per action.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    /// Arrow keys, `/` to search and letters for the password actions.
+    #[default]
+    Default,
+    /// The default bindings, plus `j`/`k`, `gg`/`G`, `Ctrl-n`/`Ctrl-p` and `q`.
+    Vi,
+    /// The default bindings, plus `Ctrl-n`/`Ctrl-p`, `Alt-<`/`Alt->`, `Ctrl-s` and `Ctrl-g`.
+    Emacs,
+}
+
+/// What the keys pressed so far mean in a [`Keymap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lookup<A> {
+    /// The keys are bound to this action.
+    Action(A),
+    /// The keys start a longer binding.
+    Pending,
+    /// The keys are bound to nothing.
+    Unbound,
+}
+
+/// Key sequences bound to the actions of a view.
+#[derive(Debug, Clone)]
+pub struct Keymap<A> {
+    bindings: Vec<(KeySequence, A)>,
+}
+
+impl<A: Action> Keymap<A> {
+    fn from_spec(spec: &[(&str, A)]) -> Self {
+        let bindings = spec
+            .iter()
+            .map(|(keys, action)| (keys.parse().expect("invalid built-in binding"), *action))
+            .collect();
+        Self { bindings }
+    }
+
+    /// Replaces the bindings of `action`, an empty list unbinding it.
+    pub fn bind(&mut self, action: A, sequences: &[KeySequence]) {
+        self.bindings.retain(|(_, bound)| *bound != action);
+        self.bindings
+            .extend(sequences.iter().map(|keys| (keys.clone(), action)));
+    }
+
+    /// Returns the action bound to the keys pressed so far, or whether they start a binding.
+    pub fn lookup(&self, keys: &[KeyChord]) -> Lookup<A> {
+        if let Some((_, action)) = self.bindings.iter().find(|(seq, _)| seq.chords() == keys) {
+            Lookup::Action(*action)
+        } else if self
+            .bindings
+            .iter()
+            .any(|(seq, _)| seq.chords().starts_with(keys))
+        {
+            Lookup::Pending
+        } else {
+            Lookup::Unbound
+        }
+    }
+
+    /// Returns the key sequences bound to `action`.
+    pub fn sequences(&self, action: A) -> impl Iterator<Item = &KeySequence> {
+        self.bindings
+            .iter()
+            .filter(move |(_, bound)| *bound == action)
+            .map(|(keys, _)| keys)
+    }
+
+    /// Returns the first key sequence bound to `action`, shown in hints.
+    pub fn hint(&self, action: A) -> Option<&KeySequence> {
+        self.sequences(action).next()
+    }
+
+    /// Returns the bound actions along with their key sequences, e.g. `j, ↓`, in the order of
+    /// [`Action::ALL`].
+    pub fn help(&self) -> Vec<(String, A)> {
+        A::ALL
+            .iter()
+            .filter_map(|action| {
+                let keys: Vec<String> = self.sequences(*action).map(|k| k.to_string()).collect();
+                (!keys.is_empty()).then(|| (keys.join(", "), *action))
+            })
+            .collect()
+    }
+}
+
+impl Keymap<SelectorAction> {
+    /// Returns the bindings of the selector for a preset.
+    pub fn selector(preset: Preset) -> Self {
+        use SelectorAction::*;
+
+        let mut keymap = Self::from_spec(&[
+            ("up", Up),
+            ("down", Down),
+            ("home", Top),
+            ("end", Bottom),
+            ("enter", Connect),
+            ("/", Search),
+            ("esc", Quit),
+            ("e", Edit),
+            ("d", Forget),
+            ("r", Reveal),
+            ("?", Help),
+        ]);
+        let extra = match preset {
+            Preset::Default => Self::from_spec(&[]),
+            Preset::Vi => Self::from_spec(&[
+                ("k", Up),
+                ("ctrl-p", Up),
+                ("j", Down),
+                ("ctrl-n", Down),
+                ("g g", Top),
+                ("G", Bottom),
+                ("q", Quit),
+            ]),
+            Preset::Emacs => Self::from_spec(&[
+                ("ctrl-p", Up),
+                ("ctrl-n", Down),
+                ("alt-<", Top),
+                ("alt->", Bottom),
+                ("ctrl-s", Search),
+                ("ctrl-g", Quit),
+            ]),
+        };
+        keymap.bindings.extend(extra.bindings);
+        keymap
+    }
+}
+
+impl Keymap<TerminalAction> {
+    /// Returns the bindings of the terminal view.
+    pub fn terminal() -> Self {
+        Self::from_spec(&[
+            // unused by the clients, unlike `ctrl-]`, the escape key of telnet
+            ("f12", TerminalAction::SendSudoPassword),
+            ("esc", TerminalAction::CancelScript),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(s: &str) -> KeyChord {
+        s.parse().unwrap()
+    }
+
+    fn keys(s: &str) -> Vec<KeyChord> {
+        s.parse::<KeySequence>().unwrap().chords().to_vec()
+    }
+
+    #[test]
+    fn parses_chords() {
+        assert_eq!(
+            chord("j"),
+            KeyChord::new(KeyCode::Char('j'), KeyModifiers::NONE)
+        );
+        assert_eq!(chord("shift-g"), chord("G"));
+        assert_eq!(
+            chord("CTRL-n"),
+            KeyChord::new(KeyCode::Char('n'), KeyModifiers::CONTROL)
+        );
+        assert_eq!(
+            chord("ctrl--"),
+            KeyChord::new(KeyCode::Char('-'), KeyModifiers::CONTROL)
+        );
+        assert_eq!(
+            chord("f12"),
+            KeyChord::new(KeyCode::F(12), KeyModifiers::NONE)
+        );
+        assert_eq!(chord("space"), chord(" "));
+        assert_eq!(chord("pgdn"), chord("pagedown"));
+        assert_eq!(chord("alt-<").to_string(), "Alt-<");
+        assert_eq!("g g".parse::<KeySequence>().unwrap().to_string(), "gg");
+        assert_eq!(
+            "ctrl-x ctrl-c".parse::<KeySequence>().unwrap().to_string(),
+            "Ctrl-x Ctrl-c"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_chords() {
+        for s in ["hyper-x", "f13", "f0", "foo", "ctrl-", "ctrl-foo"] {
+            assert!(s.parse::<KeyChord>().is_err(), "{}", s);
+        }
+        assert!("".parse::<KeySequence>().is_err());
+        assert!("  ".parse::<KeySequence>().is_err());
+        assert!("g foo".parse::<KeySequence>().is_err());
+    }
+
+    #[test]
+    fn sends_the_sudo_password_with_f12() {
+        let keymap = Keymap::terminal();
+        let f12 = KeyEvent::new(KeyCode::F(12), KeyModifiers::NONE);
+        assert_eq!(
+            keymap.lookup(&[KeyChord::from_event(&f12)]),
+            Lookup::Action(TerminalAction::SendSudoPassword)
+        );
+        // the escape key of telnet goes to the client
+        assert_eq!(keymap.lookup(&keys("ctrl-]")), Lookup::Unbound);
+        assert_eq!(
+            keymap
+                .hint(TerminalAction::SendSudoPassword)
+                .unwrap()
+                .to_string(),
+            "F12"
+        );
+    }
+
+    #[test]
+    fn looks_up_sequences() {
+        let keymap = Keymap::selector(Preset::Vi);
+        assert_eq!(keymap.lookup(&keys("g")), Lookup::Pending);
+        assert_eq!(
+            keymap.lookup(&keys("g g")),
+            Lookup::Action(SelectorAction::Top)
+        );
+        assert_eq!(
+            keymap.lookup(&keys("G")),
+            Lookup::Action(SelectorAction::Bottom)
+        );
+        assert_eq!(keymap.lookup(&keys("g j")), Lookup::Unbound);
+        // the presets only add to the default bindings
+        assert_eq!(
+            keymap.lookup(&keys("up")),
+            Lookup::Action(SelectorAction::Up)
+        );
+        assert_eq!(
+            Keymap::selector(Preset::Default).lookup(&keys("j")),
+            Lookup::Unbound
+        );
+    }
+
+    #[test]
+    fn overrides_the_bindings_of_a_preset() {
+        let mut keymap = Keymap::selector(Preset::Emacs);
+        keymap.bind(SelectorAction::Up, &["ctrl-k".parse().unwrap()]);
+        keymap.bind(SelectorAction::Forget, &[]);
+        assert_eq!(
+            keymap.lookup(&keys("ctrl-k")),
+            Lookup::Action(SelectorAction::Up)
+        );
+        assert_eq!(keymap.lookup(&keys("ctrl-p")), Lookup::Unbound);
+        assert_eq!(keymap.lookup(&keys("up")), Lookup::Unbound);
+        assert_eq!(keymap.lookup(&keys("d")), Lookup::Unbound);
+        // the other bindings of the preset are kept
+        assert_eq!(
+            keymap.lookup(&keys("ctrl-n")),
+            Lookup::Action(SelectorAction::Down)
+        );
+        let help = keymap.help();
+        assert!(help.contains(&("Ctrl-k".to_string(), SelectorAction::Up)));
+        assert!(!help
+            .iter()
+            .any(|(_, action)| *action == SelectorAction::Forget));
+    }
+}
@@ -4,6 +4,9 @@
 //! # Features
 //! - Intuitive TUI interface for selecting and searching from a large list of SSH servers.
 //! - Automatically memorizes and encrypts passwords, requiring password entry only once.
+//! - Memorizes the passphrases of SSH keys, keyed by their identity file.
+//! - Answers second-factor prompts with TOTP codes generated from an enrolled secret.
+//! - Runs expect/send login scripts attached to hosts before handing the session to the user.
 //!
 //! # Usage
 //! ```shell
@@ -16,19 +19,52 @@
 //! 3. `fssh` spawns a new TTY and runs the SSH client to connect to the chosen host.
 //! 4. If the host requires a password, `fssh` will memorize and encrypt it locally. The next time the user connects to the same host, they won't need to enter the password again.
 //! 5. If the host doesn't require a password, `fssh` will connect directly.
+#[cfg(unix)]
+mod agent;
 mod app;
+mod bundle;
+mod config;
 mod db;
+mod doctor;
 mod encrypt;
 mod input;
+mod keymap;
+mod orphan;
+mod passphrase;
+mod prompt;
 mod pty;
+mod script;
+mod secret;
 mod select_box;
 mod sshconfig;
+mod store;
 mod terminal;
+mod theme;
+mod totp;
 
-pub use app::App;
-pub use db::Db;
-pub use encrypt::EncryptionManager;
-pub use pty::{CommandBuilder, PseudoTerminal};
-pub use select_box::SelectBox;
+#[cfg(unix)]
+pub use agent::{Agent, AgentClient};
+pub use app::{App, Search};
+pub use bundle::{Bundle, Conflict};
+pub use config::{Config, Expiry, HostConfig, KeyConfig, PromptConfig, ThemeConfig};
+pub use db::{Db, DB_VERSION};
+pub use doctor::doctor;
+pub use encrypt::{seal, unseal, Cipher, EncryptError, EncryptionManager, KeyId};
+pub use keymap::{
+    Action, KeyChord, KeySequence, Keymap, Lookup, Preset, SelectorAction, TerminalAction,
+};
+pub use orphan::{find_orphans, Orphan};
+pub use prompt::PromptPatterns;
+pub use pty::{Captured, CommandBuilder, Login, PseudoTerminal};
+pub use script::{LoginScript, ScriptStep};
+#[cfg(unix)]
+pub use secret::disable_core_dumps;
+pub use secret::{SecretBytes, SecretString};
+pub use select_box::{fuzzy_match, SelectBox, Selection};
 pub use sshconfig::*;
+pub use store::{
+    AgeStore, FileStore, HostKey, PassStore, SecretKey, SecretStore, Stamps, StoreConfig,
+};
 pub use terminal::Terminal;
+pub use theme::{ColorSupport, StyleConfig, Theme, ThemeColor, ThemePreset};
+pub use totp::Totp;
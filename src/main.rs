@@ -1,10 +1,185 @@
-use std::error::Error;
+use std::{error::Error, path::PathBuf, process::ExitCode};
 
-use fssh::{retrive_ssh_configs, App};
+use clap::{Parser, Subcommand};
+use fssh::{read_ssh_configs, App, Config, Conflict, Search};
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut app = App::new(retrive_ssh_configs()?);
-    app.run()?;
+/// Connect quickly to your SSH servers.
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Connect to the host with this alias, or the only host whose alias starts with it, otherwise
+    /// search for it in the selector.
+    ///
+    /// An alias named like a subcommand (`list`, `connect`, `gc`...) runs the subcommand, use
+    /// `fssh connect <ALIAS>` for such hosts.
+    #[arg(conflicts_with = "query")]
+    target: Option<String>,
+    /// Open the selector even if the target names a host.
+    #[arg(long, requires = "target")]
+    no_auto_accept: bool,
+    /// Match the hosts against a fuzzy query, like the search of the selector.
+    #[arg(short, long, default_value = "")]
+    query: String,
+    /// Print the matching hosts instead of opening the selector, failing if none matches.
+    #[arg(short, long)]
+    filter: bool,
+    /// Print the matching hosts with a template of `{host}`, `{user}`, `{hostname}` and `{port}`.
+    #[arg(long, requires = "filter")]
+    format: Option<String>,
+    /// Connect without the selector when a single host matches the query.
+    #[arg(short = '1', long = "select-1")]
+    select_one: bool,
+    /// Fail without the selector when no host matches the query.
+    #[arg(short = '0', long = "exit-0")]
+    exit_zero: bool,
+    /// Options of `ssh`, then the remote command to run.
+    #[arg(last = true)]
+    ssh_args: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the hosts of the SSH config and the kinds of secrets stored for them.
+    List {
+        /// Print JSON instead of a table.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Connect to a host without the selector, exiting with the status of the SSH client.
+    Connect {
+        /// The alias of the host in the SSH config.
+        alias: String,
+        /// Options of `ssh`, then the remote command to run.
+        #[arg(last = true)]
+        ssh_args: Vec<String>,
+    },
+    /// Forget the stored password of a host.
+    Forget {
+        /// The alias of the host in the SSH config.
+        alias: String,
+    },
+    /// Enroll the TOTP secret answering the verification code prompts of a host.
+    Totp {
+        /// The alias of the host in the SSH config.
+        alias: String,
+        /// Remove the enrolled secret instead.
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Store a named secret that login scripts can send.
+    Secret {
+        /// The name referred to by the `secret` field of script steps.
+        name: String,
+        /// Remove the secret instead.
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Protect the encryption key with a master passphrase, or change it.
+    Passphrase {
+        /// Store the key unprotected again.
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Remove the secrets of hosts missing from the SSH config and of identity files that don't exist.
+    Gc {
+        /// Only list the secrets that would be removed.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Write the stored secrets to a file protected by a passphrase, to import them on another machine.
+    Export {
+        /// The file to write.
+        file: PathBuf,
+    },
+    /// Merge the secrets of a file written by `fssh export` into the store.
+    Import {
+        /// The file to read.
+        file: PathBuf,
+        /// What to do with the secrets differing from the stored ones.
+        #[arg(long, value_enum, default_value_t)]
+        on_conflict: Conflict,
+    },
+    /// Generate a new encryption key and re-encrypt the stored secrets with it.
+    RotateKey,
+    /// Check the configuration, the stored files and the programs fssh runs.
+    Doctor,
+    /// Unlock the key once and keep it in a background agent.
+    #[cfg(unix)]
+    Agent {
+        /// Seconds of inactivity after which the agent forgets the key.
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// Serve the key handed over on stdin, used internally.
+        #[arg(long, hide = true)]
+        serve: bool,
+    },
+    /// Make the running agent forget the key.
+    #[cfg(unix)]
+    Lock,
+}
+
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let cli = Cli::parse();
+    // every command handles secrets
+    #[cfg(unix)]
+    fssh::disable_core_dumps()?;
+    // reports broken configurations instead of failing to load them
+    if let Some(Command::Doctor) = cli.command {
+        return Ok(if fssh::doctor()? {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+    let (query, select_alias) = match cli.target {
+        Some(target) => (target, !cli.no_auto_accept),
+        None => (cli.query, false),
+    };
+    let config = Config::load(Config::default_path())?;
+    let mut app = App::new(read_ssh_configs(&config.ssh_configs)?, config);
+    app.set_ssh_args(cli.ssh_args);
+
+    match cli.command {
+        Some(Command::List { json }) => app.list(json)?,
+        Some(Command::Connect { alias, ssh_args }) => {
+            app.set_ssh_args(ssh_args);
+            // like ssh, 255 when the client didn't exit by itself
+            let code = app.connect_host(&alias)?.unwrap_or(255);
+            return Ok(ExitCode::from(u8::try_from(code).unwrap_or(u8::MAX)));
+        }
+        Some(Command::Forget { alias }) => app.forget(&alias)?,
+        Some(Command::Doctor) => unreachable!("handled before loading the configuration"),
+        Some(Command::Totp { alias, remove }) => app.enroll_totp(&alias, remove)?,
+        Some(Command::Secret { name, remove }) => app.store_secret(&name, remove)?,
+        Some(Command::Passphrase { remove }) => app.set_master_passphrase(remove)?,
+        Some(Command::Gc { dry_run }) => app.gc(dry_run)?,
+        Some(Command::Export { file }) => app.export(&file)?,
+        Some(Command::Import { file, on_conflict }) => app.import(&file, on_conflict)?,
+        Some(Command::RotateKey) => app.rotate_key()?,
+        #[cfg(unix)]
+        Some(Command::Agent { timeout, serve }) => {
+            if serve {
+                app.serve_agent(timeout)?
+            } else {
+                app.start_agent(timeout)?
+            }
+        }
+        #[cfg(unix)]
+        Some(Command::Lock) => app.lock_agent()?,
+        None if cli.filter => {
+            if !app.filter(&query, cli.format.as_deref()) {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        None => app.run(&Search {
+            query,
+            select_one: cli.select_one,
+            select_alias,
+            exit_zero: cli.exit_zero,
+        })?,
+    }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
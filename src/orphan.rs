@@ -0,0 +1,102 @@
+use std::collections::HashSet;
+
+use crate::{
+    sshconfig::SshConfigItem,
+    store::{HostKey, SecretKey, SecretStore},
+};
+
+/// A stored secret whose host, or identity file, no longer exists.
+#[derive(Debug, Clone)]
+pub struct Orphan {
+    /// The key of the secret.
+    pub key: SecretKey,
+    /// The current host closest to the one of the secret, which has no secret of this kind yet.
+    pub candidate: Option<HostKey>,
+}
+
+impl Orphan {
+    /// Returns the key of the secret moved to the candidate host.
+    pub fn reassociated(&self) -> Option<SecretKey> {
+        let host = self.candidate.clone()?;
+        match self.key {
+            SecretKey::Password(_) => Some(SecretKey::Password(host)),
+            SecretKey::Totp(_) => Some(SecretKey::Totp(host)),
+            _ => None,
+        }
+    }
+
+    /// Moves the secret to the candidate host with its stamps, returning whether there was one.
+    pub fn reassociate(&self, store: &mut dyn SecretStore) -> anyhow::Result<bool> {
+        let Some(key) = self.reassociated() else {
+            return Ok(false);
+        };
+        store.rename(&self.key, &key)
+    }
+}
+
+/// Returns the stored secrets of hosts missing from the SSH config and of identity files that don't exist.
+pub fn find_orphans(keys: &[SecretKey], hosts: &[SshConfigItem]) -> Vec<Orphan> {
+    let current: HashSet<HostKey> = hosts.iter().map(HostKey::from).collect();
+    // a host can take a single secret of each kind
+    let taken: HashSet<&SecretKey> = keys.iter().collect();
+    let mut new_keys = Vec::new();
+
+    let mut orphans = Vec::new();
+    for key in keys {
+        let candidate = match key {
+            SecretKey::Password(host) | SecretKey::Totp(host) if !current.contains(host) => {
+                closest(host, hosts, |candidate| {
+                    let key = match key {
+                        SecretKey::Password(_) => SecretKey::Password(candidate.clone()),
+                        _ => SecretKey::Totp(candidate.clone()),
+                    };
+                    !taken.contains(&key) && !new_keys.contains(&key)
+                })
+            }
+            SecretKey::Passphrase(path) if !path.exists() => None,
+            _ => continue,
+        };
+
+        let orphan = Orphan {
+            key: key.clone(),
+            candidate,
+        };
+        if let Some(key) = orphan.reassociated() {
+            new_keys.push(key);
+        }
+        orphans.push(orphan);
+    }
+    orphans
+}
+
+/// Returns the current host most likely to be `host` after an edit of the SSH config, among the
+/// ones accepted by `free`.
+///
+/// The same alias means that the user, hostname or port changed; the same user and hostname means
+/// that the alias was renamed.
+fn closest(
+    host: &HostKey,
+    hosts: &[SshConfigItem],
+    free: impl Fn(&HostKey) -> bool,
+) -> Option<HostKey> {
+    hosts
+        .iter()
+        .map(HostKey::from)
+        .filter(|candidate| free(candidate))
+        .filter_map(|candidate| {
+            let score = if candidate.alias == host.alias {
+                3
+            } else if candidate.user == host.user && candidate.hostname == host.hostname {
+                2
+            } else if candidate.hostname == host.hostname {
+                1
+            } else {
+                return None;
+            };
+            Some((score, candidate))
+        })
+        // the first host of the config wins among equally close ones
+        .rev()
+        .max_by_key(|(score, _)| *score)
+        .map(|(_, candidate)| candidate)
+}
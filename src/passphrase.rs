@@ -0,0 +1,69 @@
+use std::io::Write;
+
+use crossterm::event::{self, Event, KeyCode};
+use ratatui::{prelude::*, widgets::*};
+
+use crate::{input::SecretInput, secret::SecretString, terminal::Terminal, theme::ColorSupport};
+
+const MASK: char = '•';
+const INFO_TEXT: &str = "(Esc) cancel | (Enter) confirm";
+
+/// Prompts for a passphrase in an inline box, masking the input.
+///
+/// Returns `None` if the user cancelled.
+pub(crate) fn prompt_passphrase(
+    title: &str,
+    error: Option<&str>,
+) -> anyhow::Result<Option<SecretString>> {
+    let mut terminal = Terminal::new(Some(5), false)?;
+    let mut input = SecretInput::new();
+
+    let passphrase = loop {
+        draw(&mut terminal, title, error, &input)?;
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Esc => break None,
+                KeyCode::Enter => break Some(input.take()),
+                _ => input.handle_event(Event::Key(key)),
+            }
+        }
+    };
+
+    terminal.clear()?;
+    Result::Ok(passphrase)
+}
+
+fn draw(
+    terminal: &mut Terminal<impl Write>,
+    title: &str,
+    error: Option<&str>,
+    input: &SecretInput,
+) -> anyhow::Result<()> {
+    terminal.draw(|f| {
+        let recs = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .split(f.size());
+
+        let masked: String = input.value().chars().map(|_| MASK).collect();
+        let field = Paragraph::new(masked).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!(" {} ", title)),
+        );
+        field.render(recs[0], f.buffer_mut());
+
+        if let Some(error) = error {
+            let style = ColorSupport::detect().adapt(Style::default().fg(Color::Red));
+            Paragraph::new(Line::from(error).style(style)).render(recs[1], f.buffer_mut());
+        }
+        Paragraph::new(Line::from(INFO_TEXT))
+            .centered()
+            .render(recs[2], f.buffer_mut());
+
+        f.set_cursor(recs[0].x + 1 + input.visual_cursor() as u16, recs[0].y + 1);
+    })?;
+    Result::Ok(())
+}
@@ -0,0 +1,328 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use regex::Regex;
+
+use crate::{config::PromptConfig, secret::SecretString};
+
+/// Built-in password prompts covering OpenSSH, Dropbear, PAM and common appliances.
+const DEFAULT_PASSWORD: &[&str] = &[r"(?i)password:", r"(?i)password for [^:\r\n]+:"];
+/// Built-in key passphrase prompts, the first capture group is the identity file path.
+const DEFAULT_PASSPHRASE: &[&str] = &[r"Enter passphrase for key '([^']+)':"];
+/// Built-in second-factor prompts answered with a TOTP code.
+const DEFAULT_TOTP: &[&str] = &[
+    r"(?i)verification code:",
+    r"(?i)(one-time|otp) (password|code)[^:\r\n]*:",
+    r"(?i)authenticator code:",
+];
+/// Built-in `sudo` prompts inside an established session.
+const DEFAULT_SUDO: &[&str] = &[r"\[sudo\] password for [^:\r\n]+:"];
+/// Built-in markers of a successful login.
+const DEFAULT_SUCCESS: &[&str] = &[
+    r"Last login",
+    r"(?i)last successful login",
+    r"(?m)^[\w.@:~/\[\] -]+[$#>] ?",
+];
+/// Built-in markers of a rejected login.
+const DEFAULT_FAILURE: &[&str] = &[
+    r"(?i)permission denied",
+    r"(?i)access denied",
+    r"(?i)authentication failed",
+    r"(?i)login incorrect",
+    r"(?i)bad passwords",
+];
+
+/// Compiled regexes used to recognize prompts and login results in the pty output.
+#[derive(Debug, Clone)]
+pub struct PromptPatterns {
+    username: Vec<Regex>,
+    password: Vec<Regex>,
+    passphrase: Vec<Regex>,
+    totp: Vec<Regex>,
+    sudo: Vec<Regex>,
+    success: Vec<Regex>,
+    failure: Vec<Regex>,
+}
+
+impl Default for PromptPatterns {
+    fn default() -> Self {
+        Self {
+            // the user name is only sent to the prompts configured for a host
+            username: Vec::new(),
+            password: compile_defaults(DEFAULT_PASSWORD),
+            passphrase: compile_defaults(DEFAULT_PASSPHRASE),
+            totp: compile_defaults(DEFAULT_TOTP),
+            sudo: compile_defaults(DEFAULT_SUDO),
+            success: compile_defaults(DEFAULT_SUCCESS),
+            failure: compile_defaults(DEFAULT_FAILURE),
+        }
+    }
+}
+
+impl PromptPatterns {
+    /// Extends the patterns with the user defined ones in `config`.
+    ///
+    /// User patterns take precedence over the built-in defaults.
+    pub fn extend(&mut self, config: &PromptConfig) -> anyhow::Result<()> {
+        prepend(&mut self.username, &config.username)?;
+        prepend(&mut self.password, &config.password)?;
+        prepend(&mut self.passphrase, &config.passphrase)?;
+        if let Some(re) = self.passphrase.iter().find(|re| re.captures_len() < 2) {
+            anyhow::bail!("Passphrase pattern `{}` must capture the key path", re);
+        }
+        prepend(&mut self.totp, &config.totp)?;
+        prepend(&mut self.sudo, &config.sudo)?;
+        prepend(&mut self.success, &config.success)?;
+        prepend(&mut self.failure, &config.failure)?;
+        Ok(())
+    }
+
+    /// Returns whether `output` contains a username prompt.
+    pub fn is_username_prompt(&self, output: &str) -> bool {
+        self.username.iter().any(|re| re.is_match(output))
+    }
+
+    /// Returns whether `output` contains a password prompt.
+    ///
+    /// Second-factor prompts such as `One-time password:` and `sudo` prompts are not password prompts.
+    pub fn is_password_prompt(&self, output: &str) -> bool {
+        self.password_prompt_ends(output).next().is_some()
+    }
+
+    /// Returns whether `output` contains a second-factor prompt.
+    pub fn is_totp_prompt(&self, output: &str) -> bool {
+        self.totp.iter().any(|re| re.is_match(output))
+    }
+
+    /// Returns the identity file path if `output` contains a key passphrase prompt.
+    pub fn passphrase_prompt(&self, output: &str) -> Option<PathBuf> {
+        self.passphrase
+            .iter()
+            .find_map(|re| re.captures(output))
+            .and_then(|caps| caps.get(1))
+            .map(|m| PathBuf::from(m.as_str()))
+    }
+
+    /// Returns whether `output` contains a `sudo` prompt.
+    pub fn is_sudo_prompt(&self, output: &str) -> bool {
+        self.sudo.iter().any(|re| re.is_match(output))
+    }
+
+    /// Returns whether `output` indicates a successful login.
+    pub fn is_success(&self, output: &str) -> bool {
+        self.success.iter().any(|re| re.is_match(output))
+    }
+
+    /// Returns whether `output` indicates a rejected login.
+    pub fn is_failure(&self, output: &str) -> bool {
+        self.failure.iter().any(|re| re.is_match(output))
+    }
+
+    /// Extracts the password typed after the last password prompt in `transcript`.
+    ///
+    /// The password is only returned if the output following it indicates a successful login.
+    pub fn capture_password(&self, transcript: &str) -> Option<SecretString> {
+        let start = self.password_prompt_ends(transcript).max()?;
+
+        let (passwd, after) = answer_at(transcript, start)?;
+        if !self.succeeded(after) {
+            return None;
+        }
+        Some(SecretString::new(passwd.to_string()))
+    }
+
+    /// Extracts the passphrases typed after the last passphrase prompt of each identity file.
+    ///
+    /// A passphrase is only returned if the login succeeded right after it.
+    pub fn capture_passphrases(&self, transcript: &str) -> Vec<(PathBuf, SecretString)> {
+        let mut last: HashMap<PathBuf, usize> = HashMap::new();
+        for re in self.passphrase.iter() {
+            for caps in re.captures_iter(transcript) {
+                // the unwraps are safe because every pattern has a capture group
+                let end = caps.get(0).unwrap().end();
+                let path = PathBuf::from(caps.get(1).unwrap().as_str());
+                let start = last.entry(path).or_insert(end);
+                *start = (*start).max(end);
+            }
+        }
+
+        last.into_iter()
+            .filter_map(|(path, start)| {
+                let (passphrase, after) = answer_at(transcript, start)?;
+                self.succeeded(after)
+                    .then(|| (path, SecretString::new(passphrase.to_string())))
+            })
+            .collect()
+    }
+
+    /// Returns the end offsets of the password prompts in `output`.
+    fn password_prompt_ends<'a>(&'a self, output: &'a str) -> impl Iterator<Item = usize> + 'a {
+        self.password
+            .iter()
+            .flat_map(move |re| re.find_iter(output))
+            .filter(move |m| {
+                let line_start = output[..m.start()].rfind('\n').map_or(0, |i| i + 1);
+                let line = &output[line_start..m.end()];
+                !self.is_totp_prompt(line) && !self.is_sudo_prompt(line)
+            })
+            .map(|m| m.end())
+    }
+
+    /// Returns whether the login succeeded before any other prompt or failure in `output`.
+    fn succeeded(&self, output: &str) -> bool {
+        let Some(success) = self
+            .success
+            .iter()
+            .filter_map(|re| re.find(output))
+            .map(|m| m.start())
+            .min()
+        else {
+            return false;
+        };
+
+        let before = &output[..success];
+        !self.is_failure(before)
+            && !self.is_password_prompt(before)
+            && self.passphrase_prompt(before).is_none()
+    }
+}
+
+/// Splits `transcript` at `start` into the trimmed answer line and the output following it.
+fn answer_at(transcript: &str, start: usize) -> Option<(&str, &str)> {
+    let rest = &transcript[start..];
+    let end = rest.find('\n')?;
+    let answer = rest[..end].trim();
+    if answer.is_empty() {
+        return None;
+    }
+    Some((answer, &rest[end..]))
+}
+
+fn compile_defaults(patterns: &[&str]) -> Vec<Regex> {
+    patterns
+        .iter()
+        .map(|p| Regex::new(p).expect("built-in prompt pattern is valid"))
+        .collect()
+}
+
+fn prepend(regexes: &mut Vec<Regex>, patterns: &[String]) -> anyhow::Result<()> {
+    let mut compiled = patterns
+        .iter()
+        .map(|p| {
+            Regex::new(p).map_err(|e| anyhow::anyhow!("Invalid prompt pattern `{}`: {}", p, e))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    compiled.append(regexes);
+    *regexes = compiled;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captured(passwd: Option<SecretString>) -> Option<String> {
+        passwd.map(|passwd| passwd.to_string())
+    }
+
+    #[test]
+    fn success_markers() {
+        let prompts = PromptPatterns::default();
+        assert!(prompts.is_success("Last login: Mon Oct 12 10:00:00 2026 from 10.0.0.1\r\n"));
+        assert!(prompts.is_success("\r\nadmin@db:~$ "));
+        assert!(prompts.is_success("[root@db /]# "));
+        assert!(!prompts.is_success("Welcome to the bastion, all sessions are recorded.\r\n"));
+        assert!(!prompts.is_success("admin@db's password: "));
+    }
+
+    #[test]
+    fn second_factor_and_sudo_prompts_are_not_password_prompts() {
+        let prompts = PromptPatterns::default();
+        assert!(prompts.is_password_prompt("admin@db's password: "));
+        assert!(prompts.is_password_prompt("Password for admin@db: "));
+        assert!(!prompts.is_password_prompt("One-time password (OATH) for `admin': "));
+        assert!(prompts.is_totp_prompt("One-time password (OATH) for `admin': "));
+        assert!(!prompts.is_password_prompt("[sudo] password for admin: "));
+        assert!(prompts.is_sudo_prompt("[sudo] password for admin: "));
+    }
+
+    #[test]
+    fn captures_the_password_of_a_successful_login() {
+        let prompts = PromptPatterns::default();
+        let transcript = "admin@db's password: secret\r\nLast login: today\r\n$ ";
+        assert_eq!(
+            captured(prompts.capture_password(transcript)),
+            Some("secret".into())
+        );
+
+        // the last password typed is the accepted one
+        let transcript = "admin@db's password: old\r\nPermission denied, please try again.\r\n\
+                          admin@db's password: new\r\nLast login: today\r\n$ ";
+        assert_eq!(
+            captured(prompts.capture_password(transcript)),
+            Some("new".into())
+        );
+
+        // the second factor is typed between the password and the shell
+        let transcript =
+            "admin@db's password: secret\r\nVerification code: 123456\r\nLast login: today\r\n";
+        assert_eq!(
+            captured(prompts.capture_password(transcript)),
+            Some("secret".into())
+        );
+    }
+
+    #[test]
+    fn ignores_rejected_and_unfinished_passwords() {
+        let prompts = PromptPatterns::default();
+        let rejected = "admin@db's password: wrong\r\nPermission denied (publickey,password).\r\n";
+        assert_eq!(captured(prompts.capture_password(rejected)), None);
+        let unfinished = "admin@db's password: secr";
+        assert_eq!(captured(prompts.capture_password(unfinished)), None);
+        let asked_again = "admin@db's password: wrong\r\nadmin@db's password: \r\n";
+        assert_eq!(captured(prompts.capture_password(asked_again)), None);
+        let sudo = "$ sudo ls\r\n[sudo] password for admin: secret\r\nLast login: today\r\n";
+        assert_eq!(captured(prompts.capture_password(sudo)), None);
+    }
+
+    #[test]
+    fn captures_the_passphrases_of_a_successful_login() {
+        let prompts = PromptPatterns::default();
+        let transcript = "Enter passphrase for key '/home/admin/.ssh/id_ed25519': typo\r\n\
+                          Enter passphrase for key '/home/admin/.ssh/id_ed25519': right\r\n\
+                          Last login: today\r\n";
+        let passphrases = prompts
+            .capture_passphrases(transcript)
+            .into_iter()
+            .map(|(path, passphrase)| (path, passphrase.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            passphrases,
+            [(
+                PathBuf::from("/home/admin/.ssh/id_ed25519"),
+                "right".to_string()
+            )]
+        );
+
+        let rejected = "Enter passphrase for key '/home/admin/.ssh/id_rsa': wrong\r\n\
+                        admin@db's password: \r\n";
+        assert!(prompts.capture_passphrases(rejected).is_empty());
+    }
+
+    #[test]
+    fn user_patterns_take_precedence() {
+        let mut prompts = PromptPatterns::default();
+        let config: PromptConfig =
+            toml::from_str("success = ['(?m)^switch-[a-z0-9-]+[>#]']\nusername = ['login:']")
+                .unwrap();
+        prompts.extend(&config).unwrap();
+        assert!(prompts.is_success("switch-core-1#"));
+        assert!(prompts.is_username_prompt("login: "));
+
+        // the path of the key is the name of its passphrase
+        let config = PromptConfig {
+            passphrase: vec!["Key passphrase:".into()],
+            ..Default::default()
+        };
+        assert!(prompts.extend(&config).is_err());
+    }
+}
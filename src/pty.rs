@@ -1,6 +1,11 @@
 use std::{
+    collections::{HashMap, HashSet},
     io::Write,
-    sync::{atomic::AtomicBool, Mutex},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
     thread::sleep,
     time::Duration,
 };
@@ -10,7 +15,6 @@ pub use portable_pty::CommandBuilder;
 use portable_pty::{native_pty_system, MasterPty, PtySize};
 use std::sync::{Arc, RwLock};
 
-use bytes::Bytes;
 use tokio::{
     sync::mpsc::{channel, Sender},
     task::spawn_blocking,
@@ -21,11 +25,19 @@ use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
 
 use ratatui::{
     layout::Rect,
-    style::{Color, Style},
-    widgets::Block,
+    style::{Color, Modifier, Style},
+    widgets::Paragraph,
 };
 
-use crate::Terminal;
+use crate::{
+    keymap::{Action, KeyChord, Keymap, Lookup, TerminalAction},
+    prompt::PromptPatterns,
+    script::{LoginScript, ScriptAction},
+    secret::{line, SecretBytes, SecretString},
+    theme::Theme,
+    totp::Totp,
+    Terminal,
+};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Size {
@@ -39,13 +51,143 @@ impl Size {
     }
 }
 
+/// The details used to answer the login prompts of the spawned client.
+pub struct Login {
+    /// The user name sent to username prompts.
+    pub user: String,
+    /// An optional password that may be used by the SSH client.
+    pub passwd: Option<Arc<SecretString>>,
+    /// The cached passphrases of the identity files, keyed by their path.
+    pub passphrases: HashMap<PathBuf, SecretString>,
+    /// The enrolled TOTP generator answering second-factor prompts, if any.
+    pub totp: Option<Totp>,
+    /// The script started once the login succeeded, before handing the session to the user.
+    pub script: Option<LoginScript>,
+    /// Whether `sudo` prompts of the session may be answered with the password, on confirmation.
+    pub sudo: bool,
+    /// The patterns recognizing prompts and login results.
+    pub prompts: PromptPatterns,
+    /// Messages shown before the output of the client, e.g. about secrets that couldn't be decrypted.
+    pub notices: Vec<String>,
+    /// The keys of the commands handled by fssh during the session.
+    pub keys: Keymap<TerminalAction>,
+    /// The default colors of the session and the style of the hints.
+    pub theme: Theme,
+}
+
+/// The secrets typed by the user during a successful login, and how the client exited.
+#[derive(Debug, Default)]
+pub struct Captured {
+    /// The password of the host.
+    pub passwd: Option<SecretString>,
+    /// The passphrases of the identity files, along with their path.
+    pub passphrases: Vec<(PathBuf, SecretString)>,
+    /// Whether the cached password was sent and the login succeeded.
+    pub used_passwd: bool,
+    /// The identity files whose cached passphrase was sent and not asked again.
+    pub used_passphrases: Vec<PathBuf>,
+    /// The exit code of the client, unless it was killed.
+    pub exit_code: Option<u32>,
+}
+
 /// A pseudo terminal that spawns an SSH client and captures the password.
 pub struct PseudoTerminal {
     parser: Arc<RwLock<Parser>>,
-    sender: Sender<Bytes>,
+    sender: Sender<SecretBytes>,
     master: Box<dyn MasterPty>,
     terminate: Arc<AtomicBool>,
-    buffer: Arc<Mutex<String>>,
+    buffer: Arc<Mutex<SecretString>>,
+    prompts: PromptPatterns,
+    script: Arc<Mutex<Option<LoginScript>>>,
+    sudo_passwd: Option<Arc<SecretString>>,
+    sudo_pending: Arc<AtomicBool>,
+    phase: Arc<Mutex<LoginPhase>>,
+    record: Arc<AtomicBool>,
+    used_passwd: Arc<AtomicBool>,
+    sent_passphrases: Arc<Mutex<HashSet<PathBuf>>>,
+    exit_code: Arc<Mutex<Option<u32>>>,
+    keys: Keymap<TerminalAction>,
+    theme: Theme,
+}
+
+/// The capacity of the transcript searched for the typed secrets.
+const BUFFER_CAPACITY: usize = 8192;
+/// The size of the reads of the client output.
+const READ_SIZE: usize = 1024;
+/// The length past which the transcript stops growing, leaving room for the chunk being recorded,
+/// which grows up to thrice when its invalid UTF-8 is replaced.
+const RECORD_LIMIT: usize = BUFFER_CAPACITY - 3 * READ_SIZE;
+
+/// How far the login went, deciding whether the prompts are still answered.
+///
+/// The prompts of the session (`su`, `mysql -p`, a nested `ssh`) must never get the secrets, but
+/// the output before the first prompt may look like a logged in session, e.g. a banner with a
+/// `Welcome to` or a `>` line, so a success marker only ends the login after a prompt of it.
+#[derive(Debug)]
+struct LoginPhase {
+    /// Whether the prompts are still answered.
+    answering: bool,
+    /// Whether a password, passphrase or verification code prompt was shown.
+    authenticating: bool,
+    /// Whether a password or passphrase prompt was shown, answered by fssh or by the user.
+    first_factor: bool,
+    /// Whether a success marker was shown before any prompt, like the shell of a key login.
+    at_session_prompt: bool,
+}
+
+impl Default for LoginPhase {
+    fn default() -> Self {
+        Self {
+            answering: true,
+            authenticating: false,
+            first_factor: false,
+            at_session_prompt: false,
+        }
+    }
+}
+
+impl LoginPhase {
+    /// Follows the output of the client, returning whether the login just succeeded.
+    fn observe(&mut self, prompts: &PromptPatterns, output: &str) -> bool {
+        if !self.answering {
+            return false;
+        }
+        if prompts.is_password_prompt(output) || prompts.passphrase_prompt(output).is_some() {
+            self.first_factor = true;
+            self.authenticating = true;
+            self.at_session_prompt = false;
+        } else if prompts.is_totp_prompt(output) {
+            self.authenticating = true;
+            self.at_session_prompt = false;
+        } else if prompts.is_success(output) {
+            if self.authenticating {
+                self.answering = false;
+                return true;
+            }
+            self.at_session_prompt = true;
+        }
+        false
+    }
+
+    /// Returns whether the verification code prompt shown next is answered.
+    ///
+    /// The code is the second factor, so it follows a password or passphrase prompt, or comes first
+    /// when the server already accepted a key, as with `publickey,keyboard-interactive`.
+    fn answers_totp(&self) -> bool {
+        self.answering && (self.first_factor || !self.authenticating)
+    }
+
+    /// Follows the keys of the user, returning whether they answered the session, which ends the
+    /// login without prompts.
+    ///
+    /// The other keys, e.g. `yes` to the host key question, leave the prompts answered.
+    fn typed(&mut self, key: &KeyEvent) -> bool {
+        if self.answering && self.at_session_prompt && key.code == KeyCode::Enter {
+            self.answering = false;
+            return true;
+        }
+        false
+    }
 }
 
 impl PseudoTerminal {
@@ -61,17 +203,31 @@ impl PseudoTerminal {
     ///
     /// * `size` - The size of the terminal (rows and columns).
     /// * `cmd` - The command to be executed in the pseudo terminal.
-    /// * `passwd` - An optional password that may be used by the SSH client.
+    /// * `login` - The user name, the cached secrets and the prompt patterns of the host.
     ///
     /// # Returns
     ///
     /// This function returns a `Result` containing the newly created [`PseudoTerminal`] instance,
     /// or an error if the creation failed.
-    pub fn new(
-        size: Size,
-        cmd: CommandBuilder,
-        mut passwd: Option<String>,
-    ) -> anyhow::Result<Self> {
+    pub fn new(size: Size, cmd: CommandBuilder, login: Login) -> anyhow::Result<Self> {
+        let Login {
+            user,
+            mut passwd,
+            mut passphrases,
+            mut totp,
+            script,
+            sudo,
+            prompts,
+            notices,
+            keys,
+            theme,
+        } = login;
+        // the password is only kept for the session if the user opted in
+        let sudo_passwd = passwd.as_ref().filter(|_| sudo).map(Arc::clone);
+        let sudo_pending = Arc::new(AtomicBool::new(false));
+        let phase = Arc::new(Mutex::new(LoginPhase::default()));
+        let used_passwd = Arc::new(AtomicBool::new(false));
+
         let pty_system = native_pty_system();
         let pty_pair = pty_system.openpty(PtySize {
             rows: size.rows,
@@ -80,22 +236,41 @@ impl PseudoTerminal {
             pixel_height: 0,
         })?;
 
-        let parser = Arc::new(RwLock::new(Parser::new(size.rows, size.cols, 1000)));
+        let mut parser = Parser::new(size.rows, size.cols, 1000);
+        for message in notices.iter() {
+            notice(&mut parser, message);
+        }
+        let parser = Arc::new(RwLock::new(parser));
 
         let terminate = Arc::new(AtomicBool::new(false));
+        let exit_code = Arc::new(Mutex::new(None));
         {
+            let exit_code = exit_code.clone();
             // Spawning a new thread to run the command
             spawn_blocking(move || -> anyhow::Result<()> {
                 let mut child = pty_pair.slave.spawn_command(cmd)?;
-                let _ = child.wait();
+                let status = child.wait();
+                // recorded before the pty is closed, which terminates the session
+                *exit_code
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("Failed to acquire lock of exit code."))? =
+                    status.ok().map(|status| status.exit_code());
                 drop(pty_pair.slave);
                 Ok(())
             });
         }
 
-        let buffer = Arc::new(Mutex::new(String::new()));
+        // allocated once, so that no copy of the typed secrets is left behind by a reallocation
+        let buffer = Arc::new(Mutex::new(SecretString::new(String::with_capacity(
+            BUFFER_CAPACITY,
+        ))));
+        let script = Arc::new(Mutex::new(script));
+        // the transcript stops once the login succeeds, so the secrets typed in the session and by
+        // the login script aren't captured
+        let record = Arc::new(AtomicBool::new(true));
+        let sent_passphrases = Arc::new(Mutex::new(HashSet::new()));
 
-        let (tx, mut rx) = channel::<Bytes>(32);
+        let (tx, mut rx) = channel::<SecretBytes>(32);
 
         // pty reader end
         {
@@ -104,11 +279,22 @@ impl PseudoTerminal {
             let terminate = terminate.clone();
             let buffer = buffer.clone();
             let tx = tx.clone();
+            let prompts = prompts.clone();
+            let script = script.clone();
+            let record = record.clone();
+            let sudo_pending = sudo_pending.clone();
+            let phase = phase.clone();
+            let used_passwd = used_passwd.clone();
+            let sudo = sudo_passwd.is_some();
+            let send_passphrases = sent_passphrases.clone();
 
             spawn_blocking(move || -> anyhow::Result<()> {
-                let mut buf = [0; 1024];
+                let mut buf = [0; READ_SIZE];
+                let mut send_user = false;
                 let mut send_passwd = false;
                 let mut validate_passwd = false;
+                let mut rejected_passwd = false;
+                let mut send_totp = false;
 
                 loop {
                     let n = reader.read(&mut buf)?;
@@ -117,12 +303,52 @@ impl PseudoTerminal {
                     }
 
                     let string = String::from_utf8_lossy(&buf[..n]);
-
-                    if passwd.is_some() && string.contains("assword: ") {
+                    let passphrase_prompt = prompts.passphrase_prompt(&string);
+                    let (answering, answers_totp) = {
+                        let phase = phase.lock().map_err(|_| {
+                            anyhow::anyhow!("Failed to acquire lock of login phase.")
+                        })?;
+                        (phase.answering, phase.answers_totp())
+                    };
+
+                    let autofilled = if let Some((path, passphrase)) = passphrase_prompt
+                        .as_ref()
+                        .filter(|_| answering)
+                        .and_then(|path| passphrases.remove_entry(path))
+                    {
+                        tx.blocking_send(line(&passphrase))?;
+                        send_passphrases
+                            .lock()
+                            .map_err(|_| anyhow::anyhow!("Failed to acquire lock of passphrases."))?
+                            .insert(path);
+                        true
+                    } else if let Some(code) = totp
+                        .as_ref()
+                        .filter(|_| answers_totp && !send_totp)
+                        .filter(|_| prompts.is_totp_prompt(&string))
+                        .and_then(|totp| totp.now().ok())
+                    {
+                        tx.blocking_send(line(&code))?;
+                        send_totp = true;
+                        true
+                    } else if answering && passwd.is_some() && prompts.is_password_prompt(&string) {
                         // the unwrap is safe here because we have already checked
                         let passwd = passwd.take().unwrap();
-                        tx.blocking_send(Bytes::from(format!("{}\n", passwd)))?;
+                        tx.blocking_send(line(&passwd))?;
                         send_passwd = true;
+                        true
+                    } else if answering
+                        && !send_user
+                        && !send_passwd
+                        && prompts.is_username_prompt(&string)
+                    {
+                        let mut parser = parser.write().map_err(|_| {
+                            anyhow::anyhow!("Failed to acquire write lock of Parser.")
+                        })?;
+                        parser.process(&buf[..n]);
+                        tx.blocking_send(line(&user))?;
+                        send_user = true;
+                        true
                     } else {
                         if send_passwd && !validate_passwd {
                             // skip the whitespaces bytes
@@ -134,10 +360,15 @@ impl PseudoTerminal {
                             let mut parser = parser.write().map_err(|_| {
                                 anyhow::anyhow!("Failed to acquire write lock of Parser.")
                             })?;
-                            if string.contains("ermission denied") {
-                                parser.process(&Bytes::from(format!(
-                                    "\x1b[1;4mCached password is outdated, please input it again.\x1b[0m\n"
-                                )));
+                            if prompts.is_failure(&string) {
+                                rejected_passwd = true;
+                                notice(
+                                    &mut parser,
+                                    "Cached password is outdated, please input it again.",
+                                );
+                                if prompts.is_password_prompt(&string) {
+                                    parser.process(&buf[..n]);
+                                }
                             } else {
                                 parser.process(&buf[..n]);
                             }
@@ -145,21 +376,98 @@ impl PseudoTerminal {
                             let mut parser = parser.write().map_err(|_| {
                                 anyhow::anyhow!("Failed to acquire write lock of Parser.")
                             })?;
+                            // the key asks again, so the cached passphrase was rejected
+                            let rejected = passphrase_prompt.is_some_and(|path| {
+                                send_passphrases
+                                    .lock()
+                                    .is_ok_and(|mut sent| sent.remove(&path))
+                            });
+                            if rejected {
+                                notice(
+                                    &mut parser,
+                                    "Cached passphrase is outdated, please input it again.",
+                                );
+                            }
+                            // the server asks again, so fall back to manual entry
+                            if send_totp && prompts.is_totp_prompt(&string) {
+                                send_totp = false;
+                                totp = None;
+                                notice(
+                                    &mut parser,
+                                    "Generated verification code was rejected, please input it manually.",
+                                );
+                            }
                             parser.process(&buf[..n]);
                         }
+                        false
+                    };
+
+                    let (logged_in, authenticating) = {
+                        let mut phase = phase.lock().map_err(|_| {
+                            anyhow::anyhow!("Failed to acquire lock of login phase.")
+                        })?;
+                        (phase.observe(&prompts, &string), phase.authenticating)
+                    };
+
+                    // a long banner before the first prompt would fill the transcript
+                    if authenticating {
+                        let mut buffer = buffer
+                            .lock()
+                            .map_err(|_| anyhow::anyhow!("Failed to acquire lock of buffer."))?;
+                        if buffer.len() < RECORD_LIMIT && record.load(Ordering::Relaxed) {
+                            push_within_capacity(&mut buffer, &string);
+                        }
                     }
 
-                    let mut buffer = buffer
-                        .lock()
-                        .map_err(|_| anyhow::anyhow!("Failed to acquire lock of buffer."))?;
-                    if buffer.len() < 1024 {
-                        buffer.push_str(&string);
+                    // the typed secrets are captured from the login only
+                    if logged_in {
+                        used_passwd.store(send_passwd && !rejected_passwd, Ordering::Relaxed);
+                        record.store(false, Ordering::Relaxed);
+                    }
+
+                    if !autofilled && sudo && prompts.is_sudo_prompt(&string) {
+                        sudo_pending.store(true, Ordering::Relaxed);
+                    }
+
+                    if !autofilled {
+                        let mut script = script
+                            .lock()
+                            .map_err(|_| anyhow::anyhow!("Failed to acquire lock of script."))?;
+                        if let Some(runner) = script.as_mut() {
+                            if !runner.is_running() && prompts.is_success(&string) {
+                                runner.start();
+                            }
+                            let actions = if runner.is_running() {
+                                runner.feed(&string)
+                            } else {
+                                Vec::new()
+                            };
+                            for action in actions {
+                                match action {
+                                    ScriptAction::Send(data) => {
+                                        record.store(false, Ordering::Relaxed);
+                                        tx.blocking_send(data)?;
+                                    }
+                                    ScriptAction::Abort(reason) => {
+                                        let mut parser = parser.write().map_err(|_| {
+                                            anyhow::anyhow!(
+                                                "Failed to acquire write lock of Parser."
+                                            )
+                                        })?;
+                                        notice(&mut parser, &reason);
+                                    }
+                                }
+                            }
+                            if runner.is_done() {
+                                *script = None;
+                            }
+                        }
                     }
                 }
 
                 // wait for a while before rendering the remaining data
                 sleep(Duration::from_millis(10));
-                terminate.store(true, std::sync::atomic::Ordering::Relaxed);
+                terminate.store(true, Ordering::Relaxed);
                 Ok(())
             });
         }
@@ -167,6 +475,7 @@ impl PseudoTerminal {
         {
             let mut writer = pty_pair.master.take_writer().unwrap();
             let buffer = buffer.clone();
+            let record = record.clone();
             tokio::spawn(async move {
                 while let Some(data) = rx.recv().await {
                     writer.write_all(&data)?;
@@ -176,8 +485,8 @@ impl PseudoTerminal {
                         .lock()
                         .map_err(|_| anyhow::anyhow!("Failed to acquire lock of buffer."))?;
 
-                    if buffer.len() < 4096 {
-                        buffer.push_str(String::from_utf8_lossy(&data).as_ref());
+                    if buffer.len() < RECORD_LIMIT && record.load(Ordering::Relaxed) {
+                        push_within_capacity(&mut buffer, &String::from_utf8_lossy(&data));
                     }
                 }
                 Ok(())
@@ -185,14 +494,34 @@ impl PseudoTerminal {
         }
 
         Ok(Self {
-            parser: parser,
+            parser,
             sender: tx,
             master: pty_pair.master,
             terminate,
-            buffer: buffer,
+            buffer,
+            prompts,
+            script,
+            sudo_passwd,
+            sudo_pending,
+            phase,
+            record,
+            used_passwd,
+            sent_passphrases,
+            exit_code,
+            keys,
+            theme,
         })
     }
 
+    fn notice(&self, message: &str) -> anyhow::Result<()> {
+        let mut parser = self
+            .parser
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to acquire write lock of Parser."))?;
+        notice(&mut parser, message);
+        Ok(())
+    }
+
     async fn handle_key_event(&mut self, key: &KeyEvent) -> anyhow::Result<bool> {
         let input_bytes = match key.code {
             KeyCode::Char(ch) => {
@@ -243,15 +572,12 @@ impl PseudoTerminal {
             _ => return Ok(true),
         };
 
-        self.sender.send(Bytes::from(input_bytes)).await?;
+        self.sender.send(SecretBytes::new(input_bytes)).await?;
         Ok(true)
     }
 
-    /// Renders the output from the slave pty, processes input from the keyboard, and searches for the password when the pty exits.
-    pub async fn run(
-        &mut self,
-        terminal: &mut Terminal<impl Write>,
-    ) -> anyhow::Result<Option<String>> {
+    /// Renders the output from the slave pty, processes input from the keyboard, and searches for the password and key passphrases when the pty exits.
+    pub async fn run(&mut self, terminal: &mut Terminal<impl Write>) -> anyhow::Result<Captured> {
         let terminal_size = terminal.size()?;
         let mut size = Size {
             rows: terminal_size.height,
@@ -259,10 +585,27 @@ impl PseudoTerminal {
         };
 
         loop {
-            if self.terminate.load(std::sync::atomic::Ordering::Relaxed) {
+            if self.terminate.load(Ordering::Relaxed) {
                 break;
             }
 
+            // hand the session to the user if the login script is stuck
+            {
+                let mut script = self
+                    .script
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("Failed to acquire lock of script."))?;
+                if let Some(expecting) = script
+                    .as_ref()
+                    .filter(|runner| runner.is_expired())
+                    .and_then(|runner| runner.expecting())
+                {
+                    let message = format!("Login script timed out waiting for `{}`.", expecting);
+                    *script = None;
+                    self.notice(&message)?;
+                }
+            }
+
             terminal.draw(|frame| {
                 let parser = self
                     .parser
@@ -271,22 +614,87 @@ impl PseudoTerminal {
                     .unwrap();
 
                 let screen = parser.screen();
-                let block = Block::default().style(Style::default().bg(Color::Black));
-
                 let cursor = Cursor::default().visibility(true);
-                // Style
-                let pseudo_term = PseudoTerminalWidget::new(screen)
-                    .block(block)
-                    .cursor(cursor);
+                let pseudo_term = PseudoTerminalWidget::new(screen).cursor(cursor);
 
                 let rect = Rect::new(0, 0, size.cols, size.rows);
                 frame.render_widget(pseudo_term, rect);
+                // the cells without colors of their own take the colors of the theme
+                let buffer = frame.buffer_mut();
+                let area = rect.intersection(buffer.area);
+                for y in area.top()..area.bottom() {
+                    for x in area.left()..area.right() {
+                        let cell = buffer.get_mut(x, y);
+                        if let Some(fg) = self.theme.terminal.fg.filter(|_| cell.fg == Color::Reset)
+                        {
+                            cell.fg = fg;
+                        }
+                        if let Some(bg) = self.theme.terminal.bg.filter(|_| cell.bg == Color::Reset)
+                        {
+                            cell.bg = bg;
+                        }
+                    }
+                }
+
+                let confirm = self.keys.hint(TerminalAction::SendSudoPassword);
+                if let Some(confirm) =
+                    confirm.filter(|_| self.sudo_pending.load(Ordering::Relaxed) && size.rows > 0)
+                {
+                    let hint = Paragraph::new(format!(
+                        " sudo asks for a password: ({}) {} | (any other key) ignore ",
+                        confirm,
+                        TerminalAction::SendSudoPassword.description()
+                    ))
+                    .style(
+                        Style::default()
+                            .add_modifier(Modifier::REVERSED)
+                            .patch(self.theme.status),
+                    );
+                    frame.render_widget(hint, Rect::new(0, size.rows - 1, size.cols, 1));
+                }
             })?;
 
             if event::poll(Duration::from_millis(10))? {
                 match event::read()? {
                     Event::FocusLost => {}
                     Event::Key(key) => {
+                        let action = match self.keys.lookup(&[KeyChord::from_event(&key)]) {
+                            Lookup::Action(action) => Some(action),
+                            _ => None,
+                        };
+                        // the keyboard is disabled while the login script runs, except to cancel it
+                        let scripting = {
+                            let mut script = self.script.lock().map_err(|_| {
+                                anyhow::anyhow!("Failed to acquire lock of script.")
+                            })?;
+                            let running = script.as_ref().is_some_and(|s| s.is_running());
+                            if running && action == Some(TerminalAction::CancelScript) {
+                                *script = None;
+                                self.notice("Login script cancelled.")?;
+                            }
+                            running
+                        };
+                        if scripting {
+                            continue;
+                        }
+
+                        // never answer sudo without the confirmation keystroke
+                        if self.sudo_pending.swap(false, Ordering::Relaxed)
+                            && action == Some(TerminalAction::SendSudoPassword)
+                        {
+                            if let Some(passwd) = self.sudo_passwd.as_ref() {
+                                self.sender.send(line(passwd)).await?;
+                            }
+                            continue;
+                        }
+                        let answered = self
+                            .phase
+                            .lock()
+                            .map_err(|_| anyhow::anyhow!("Failed to acquire lock of login phase."))?
+                            .typed(&key);
+                        if answered {
+                            self.record.store(false, Ordering::Relaxed);
+                        }
                         self.handle_key_event(&key).await?;
                     }
                     Event::Resize(cols, rows) => {
@@ -301,8 +709,8 @@ impl PseudoTerminal {
 
                         self.master
                             .resize(PtySize {
-                                rows: rows,
-                                cols: cols,
+                                rows,
+                                cols,
                                 pixel_width: 0,
                                 pixel_height: 0,
                             })
@@ -314,20 +722,110 @@ impl PseudoTerminal {
             }
         }
 
-        let mut passwd: Option<String> = None;
-
         let buffer = self.buffer.lock().unwrap();
-        if buffer.contains("Last login") {
-            if let Some(start) = buffer.rfind("password: ") {
-                let end = buffer[start + 10..].find('\n').unwrap();
-                passwd = Some(
-                    String::from(&buffer[start + 10..start + 10 + end])
-                        .trim()
-                        .to_string(),
-                );
-            }
+        let used_passwd = self.used_passwd.load(Ordering::Relaxed);
+        let used_passphrases: Vec<PathBuf> =
+            self.sent_passphrases.lock().unwrap().drain().collect();
+        // the cached secrets are in the transcript too, only the typed ones are captured
+        let passphrases = self
+            .prompts
+            .capture_passphrases(&buffer)
+            .into_iter()
+            .filter(|(path, _)| !used_passphrases.contains(path))
+            .collect();
+        Ok(Captured {
+            passwd: self
+                .prompts
+                .capture_password(&buffer)
+                .filter(|_| !used_passwd),
+            passphrases,
+            used_passwd,
+            used_passphrases,
+            exit_code: *self.exit_code.lock().unwrap(),
+        })
+    }
+}
+
+/// Appends `data` to the transcript if it fits in the allocated capacity, which is never grown.
+fn push_within_capacity(buffer: &mut String, data: &str) {
+    if buffer.len() + data.len() <= buffer.capacity() {
+        buffer.push_str(data);
+    }
+}
+
+/// Writes a highlighted message of fssh to the terminal.
+fn notice(parser: &mut Parser, message: &str) {
+    parser.process(format!("\x1b[1;4m{}\x1b[0m\r\n", message).as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn banner_does_not_end_the_login() {
+        let prompts = PromptPatterns::default();
+        let mut phase = LoginPhase::default();
+        assert!(!phase.observe(&prompts, "Authorized users only >\r\nadmin@db:~$ \r\n"));
+        assert!(!phase.observe(&prompts, "admin@db's password: "));
+        assert!(phase.answering);
+        assert!(phase.observe(&prompts, "Last login: today\r\nadmin@db:~$ "));
+        assert!(!phase.answering);
+        // the prompts of the session are left to the user
+        assert!(!phase.observe(&prompts, "Password: "));
+        assert!(!phase.answering);
+    }
+
+    #[test]
+    fn keys_end_the_login_at_the_session_only() {
+        let prompts = PromptPatterns::default();
+        let mut phase = LoginPhase::default();
+        phase.observe(
+            &prompts,
+            "Are you sure you want to continue connecting (yes/no)? ",
+        );
+        for code in [
+            KeyCode::Char('y'),
+            KeyCode::Char('e'),
+            KeyCode::Char('s'),
+            KeyCode::Enter,
+        ] {
+            assert!(!phase.typed(&key(code)));
         }
+        assert!(phase.answering);
+
+        // a key login shows the shell without any prompt
+        phase.observe(&prompts, "Last login: today\r\nadmin@db:~$ ");
+        assert!(phase.answering);
+        assert!(!phase.typed(&key(KeyCode::Char('s'))));
+        assert!(phase.typed(&key(KeyCode::Enter)));
+        assert!(!phase.answering);
+    }
 
-        Ok(passwd)
+    #[test]
+    fn verification_code_follows_the_first_factor() {
+        let prompts = PromptPatterns::default();
+
+        // the server accepted a key before asking for the code
+        let mut phase = LoginPhase::default();
+        phase.observe(&prompts, "Welcome to the bastion\r\n");
+        assert!(phase.answers_totp());
+        phase.observe(&prompts, "Verification code: ");
+        // asked again, the code was rejected without a first factor
+        assert!(!phase.answers_totp());
+
+        // the password is typed by the user or sent by fssh alike
+        let mut phase = LoginPhase::default();
+        phase.observe(&prompts, "admin@db's password: ");
+        assert!(phase.answers_totp());
+        phase.observe(&prompts, "Verification code: ");
+        assert!(phase.answers_totp());
+        phase.observe(&prompts, "Last login: today\r\n$ ");
+        // the codes asked in the session are left to the user
+        assert!(!phase.answers_totp());
     }
 }
@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::secret::{line, SecretBytes, SecretString};
+
+/// The secret name referring to the login password of the host.
+pub const PASSWORD_SECRET: &str = "password";
+const DEFAULT_TIMEOUT: u64 = 10;
+
+/// A step of a login script: wait for `expect`, then answer with `send` or a stored `secret`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptStep {
+    /// A regex matched against the output since the previous step.
+    pub expect: String,
+    /// The line sent once `expect` matches.
+    pub send: Option<String>,
+    /// The name of a stored secret sent once `expect` matches, `password` being the login password.
+    pub secret: Option<String>,
+    /// Seconds to wait for `expect` before handing the session to the user, 10 by default.
+    pub timeout: Option<u64>,
+}
+
+impl ScriptStep {
+    /// Checks that the step is well-formed.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        Regex::new(&self.expect)
+            .map_err(|e| anyhow::anyhow!("Invalid script pattern `{}`: {}", self.expect, e))?;
+        if self.send.is_some() && self.secret.is_some() {
+            anyhow::bail!(
+                "Script step `{}` can't have both `send` and `secret`.",
+                self.expect
+            );
+        }
+        Ok(())
+    }
+}
+
+enum Answer {
+    Line(Arc<SecretString>),
+    Missing(String),
+    Nothing,
+}
+
+struct Step {
+    expect: Regex,
+    answer: Answer,
+    timeout: Duration,
+}
+
+/// What the pty should do after feeding output to a [`LoginScript`].
+pub enum ScriptAction {
+    /// Send the bytes to the pty.
+    Send(SecretBytes),
+    /// Stop the script and tell the user why.
+    Abort(String),
+}
+
+/// An expect/send login script, started once the login succeeded.
+pub struct LoginScript {
+    steps: Vec<Step>,
+    output: String,
+    deadline: Option<Instant>,
+}
+
+impl LoginScript {
+    /// Creates a [`LoginScript`] from the configured steps, resolving the secrets they refer to.
+    ///
+    /// A step referring to a secret missing from `secrets` stops the script when it's reached.
+    pub fn new(
+        steps: &[ScriptStep],
+        secrets: &HashMap<String, Arc<SecretString>>,
+    ) -> anyhow::Result<Self> {
+        let steps = steps
+            .iter()
+            .map(|step| {
+                step.validate()?;
+                let answer = match (&step.send, &step.secret) {
+                    (Some(line), _) => Answer::Line(Arc::new(SecretString::new(line.clone()))),
+                    (None, Some(name)) => match secrets.get(name) {
+                        Some(secret) => Answer::Line(Arc::clone(secret)),
+                        None => Answer::Missing(name.clone()),
+                    },
+                    (None, None) => Answer::Nothing,
+                };
+                Ok(Step {
+                    // the unwrap is safe because the step is validated
+                    expect: Regex::new(&step.expect).unwrap(),
+                    answer,
+                    timeout: Duration::from_secs(step.timeout.unwrap_or(DEFAULT_TIMEOUT)),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            steps,
+            output: String::new(),
+            deadline: None,
+        })
+    }
+
+    /// Starts waiting for the first step.
+    pub fn start(&mut self) {
+        self.deadline = self.steps.first().map(|s| Instant::now() + s.timeout);
+    }
+
+    /// Returns whether the script is started and not done yet.
+    pub fn is_running(&self) -> bool {
+        self.deadline.is_some() && !self.is_done()
+    }
+
+    /// Returns whether all the steps are done.
+    pub fn is_done(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Returns whether the current step waited for longer than its timeout.
+    pub fn is_expired(&self) -> bool {
+        !self.is_done()
+            && self
+                .deadline
+                .is_some_and(|deadline| Instant::now() > deadline)
+    }
+
+    /// Returns the pattern the script is waiting for.
+    pub fn expecting(&self) -> Option<&str> {
+        self.steps.first().map(|s| s.expect.as_str())
+    }
+
+    /// Feeds the pty output to the started script and returns the resulting actions.
+    pub fn feed(&mut self, output: &str) -> Vec<ScriptAction> {
+        self.output.push_str(output);
+
+        let mut actions = Vec::new();
+        while let Some(end) = self
+            .steps
+            .first()
+            .and_then(|step| step.expect.find(&self.output))
+            .map(|m| m.end())
+        {
+            self.output.drain(..end);
+            let step = self.steps.remove(0);
+            match step.answer {
+                Answer::Line(answer) => actions.push(ScriptAction::Send(line(&answer))),
+                Answer::Missing(name) => {
+                    self.steps.clear();
+                    actions.push(ScriptAction::Abort(format!(
+                        "Login script stopped: secret `{}` is not stored.",
+                        name
+                    )));
+                    break;
+                }
+                Answer::Nothing => {}
+            }
+            self.start();
+        }
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(expect: &str, send: Option<&str>, secret: Option<&str>) -> ScriptStep {
+        ScriptStep {
+            expect: expect.to_string(),
+            send: send.map(str::to_string),
+            secret: secret.map(str::to_string),
+            timeout: None,
+        }
+    }
+
+    /// Returns the lines sent, or the reason of an abort prefixed with `!`.
+    fn feed(script: &mut LoginScript, output: &str) -> Vec<String> {
+        script
+            .feed(output)
+            .into_iter()
+            .map(|action| match action {
+                ScriptAction::Send(data) => String::from_utf8(data.to_vec()).unwrap(),
+                ScriptAction::Abort(reason) => format!("!{}", reason),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn answers_the_steps_in_order() {
+        let secrets = HashMap::from([(
+            "enable".to_string(),
+            Arc::new(SecretString::new("s3cret".to_string())),
+        )]);
+        let steps = [
+            step(r"^.*>\s*$", Some("enable"), None),
+            step("Password:", None, Some("enable")),
+            step("#", None, None),
+            step("#", Some("terminal length 0"), None),
+        ];
+        let mut script = LoginScript::new(&steps, &secrets).unwrap();
+        assert!(!script.is_running());
+        script.start();
+        assert!(script.is_running());
+        assert_eq!(script.expecting(), Some(r"^.*>\s*$"));
+
+        assert_eq!(feed(&mut script, "switch>"), ["enable\n"]);
+        // a pattern split between two reads
+        assert!(feed(&mut script, "Pass").is_empty());
+        assert_eq!(feed(&mut script, "word: "), ["s3cret\n"]);
+        // a step without answer only waits, the next one matches the rest of the output
+        assert_eq!(
+            feed(&mut script, "switch# switch#"),
+            ["terminal length 0\n"]
+        );
+        assert!(script.is_done());
+        assert!(!script.is_running());
+        assert!(feed(&mut script, "switch#").is_empty());
+    }
+
+    #[test]
+    fn missing_secret_stops_the_script() {
+        let steps = [
+            step("Password:", None, Some("enable")),
+            step("#", Some("show version"), None),
+        ];
+        let mut script = LoginScript::new(&steps, &HashMap::new()).unwrap();
+        script.start();
+        assert_eq!(
+            feed(&mut script, "Password: "),
+            ["!Login script stopped: secret `enable` is not stored."]
+        );
+        assert!(script.is_done());
+        assert!(feed(&mut script, "switch#").is_empty());
+    }
+
+    #[test]
+    fn times_out_waiting_for_a_step() {
+        let mut steps = [step("#", Some("enable"), None)];
+        steps[0].timeout = Some(0);
+        let mut script = LoginScript::new(&steps, &HashMap::new()).unwrap();
+        // the timeout starts with the script
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(!script.is_expired());
+        script.start();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(script.is_expired());
+        assert_eq!(script.expecting(), Some("#"));
+
+        // a done script never expires
+        assert_eq!(feed(&mut script, "#"), ["enable\n"]);
+        assert!(!script.is_expired());
+    }
+
+    #[test]
+    fn session_ending_early_sends_nothing() {
+        let steps = [
+            step("Password:", Some("one"), None),
+            step("Password:", Some("two"), None),
+        ];
+        let mut script = LoginScript::new(&steps, &HashMap::new()).unwrap();
+        script.start();
+        assert_eq!(feed(&mut script, "Password: "), ["one\n"]);
+        assert!(feed(&mut script, "Connection closed.\r\n").is_empty());
+        assert!(script.is_running());
+        assert_eq!(script.expecting(), Some("Password:"));
+    }
+
+    #[test]
+    fn rejects_malformed_steps() {
+        assert!(step("(", Some("x"), None).validate().is_err());
+        assert!(step("#", Some("x"), Some("enable")).validate().is_err());
+        assert!(LoginScript::new(&[step("(", None, None)], &HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use zeroize::{Zeroize, Zeroizing};
+
+/// Secret bytes, wiped from memory when dropped.
+pub type SecretBytes = Zeroizing<Vec<u8>>;
+/// A secret string, wiped from memory when dropped.
+pub type SecretString = Zeroizing<String>;
+
+/// Converts secret bytes to a secret string without copying them.
+pub fn into_string(mut secret: SecretBytes) -> anyhow::Result<SecretString> {
+    match String::from_utf8(std::mem::take(&mut *secret)) {
+        Ok(secret) => Ok(Zeroizing::new(secret)),
+        Err(e) => {
+            e.into_bytes().zeroize();
+            anyhow::bail!("The secret is not valid UTF-8.")
+        }
+    }
+}
+
+/// Returns `secret` followed by a newline, as typed at a prompt.
+///
+/// The line is allocated once, so that no copy of the secret is left behind by a reallocation.
+pub fn line(secret: &str) -> SecretBytes {
+    let mut line = Vec::with_capacity(secret.len() + 1);
+    line.extend_from_slice(secret.as_bytes());
+    line.push(b'\n');
+    Zeroizing::new(line)
+}
+
+/// Keeps the secrets held by the process out of core dumps.
+#[cfg(unix)]
+pub fn disable_core_dumps() -> anyhow::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: the limit is a valid rlimit
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+        anyhow::bail!(
+            "Failed to disable core dumps: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    // also refuses ptrace attaches from other processes of the user
+    #[cfg(target_os = "linux")]
+    // SAFETY: PR_SET_DUMPABLE takes a single integer argument
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0) } != 0 {
+        anyhow::bail!(
+            "Failed to disable core dumps: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
@@ -1,8 +1,11 @@
-use std::io::Write;
+use std::{collections::HashMap, io::Write};
 
 use crate::input::InputBuffer;
+use crate::keymap::{Action, KeyChord, Keymap, Lookup, Preset, SelectorAction};
+use crate::secret::SecretString;
 use crate::sshconfig::SshConfigItem;
 use crate::terminal::Terminal;
+use crate::theme::Theme;
 
 use ratatui::prelude::*;
 use ratatui::widgets::*;
@@ -12,10 +15,25 @@ use unicode_width::UnicodeWidthStr;
 
 use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
 
-const INFO_TEXT_NORMAL_MODE: &str =
-    "(Esc) quit | (↑) move up | (↓) move down | (Enter) connect | (/) search";
-const INFO_TEXT_SEAERCH_MODE: &str =
-    "(Esc) quit search | (↑) move up | (↓) move down | (Enter) connect";
+/// The actions hinted at the bottom in normal mode, the others being listed by the help.
+const HINTS_NORMAL_MODE: &[SelectorAction] = &[
+    SelectorAction::Quit,
+    SelectorAction::Up,
+    SelectorAction::Down,
+    SelectorAction::Connect,
+    SelectorAction::Search,
+    SelectorAction::Edit,
+    SelectorAction::Forget,
+    SelectorAction::Reveal,
+    SelectorAction::Help,
+];
+const HINTS_SEARCH_MODE: &[SelectorAction] = &[
+    SelectorAction::Quit,
+    SelectorAction::Up,
+    SelectorAction::Down,
+    SelectorAction::Connect,
+    SelectorAction::Help,
+];
 const SEARCH_SYMBOL: &str = "🔍 ";
 
 enum Mode {
@@ -23,6 +41,20 @@ enum Mode {
     Search,
 }
 
+/// What the user chose to do with a host in the [`SelectBox`].
+#[derive(Debug, Clone)]
+pub enum Selection {
+    /// Connect to the host.
+    Connect(SshConfigItem),
+    /// Type a new password for the host.
+    Edit(SshConfigItem),
+    /// Forget the stored password of the host, confirmed by the user.
+    Forget(SshConfigItem),
+    /// Show the stored password of the host, confirmed by the user unless the master passphrase
+    /// is asked instead, see [`SelectBox::set_confirm_reveal`].
+    Reveal(SshConfigItem),
+}
+
 /// An TUI interface to select SSH configuration items.
 pub struct SelectBox {
     data: Vec<SshConfigItem>,
@@ -32,6 +64,17 @@ pub struct SelectBox {
     filtered_data: Vec<SshConfigItem>,
     input_buffer: InputBuffer,
     mode: Mode,
+    marks: HashMap<SshConfigItem, String>,
+    // may show a revealed password
+    status: Option<SecretString>,
+    // the selection waiting for the user to confirm it with `y`
+    confirming: Option<Selection>,
+    confirm_reveal: bool,
+    keymap: Keymap<SelectorAction>,
+    // the keys pressed so far of a longer binding, e.g. the first `g` of `gg`
+    pending: Vec<KeyChord>,
+    help: bool,
+    theme: Theme,
 }
 
 impl SelectBox {
@@ -40,6 +83,11 @@ impl SelectBox {
         self.data.len()
     }
 
+    /// Returns `true` if there are no items.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
     /// Creates an instance of [`SelectBox`].
     pub fn new(data: Vec<SshConfigItem>) -> Self {
         Self {
@@ -62,64 +110,162 @@ impl SelectBox {
             state: TableState::default().with_selected(0),
             input_buffer: InputBuffer::new(SEARCH_SYMBOL.to_string()),
             mode: Mode::Normal,
+            marks: HashMap::new(),
+            status: None,
+            confirming: None,
+            confirm_reveal: true,
+            keymap: Keymap::selector(Preset::default()),
+            pending: Vec::new(),
+            help: false,
+            theme: Theme::default(),
             data,
         }
     }
 
+    /// Sets the text of the `Stored` column, e.g. the kinds of secrets stored for each host.
+    pub fn set_marks(&mut self, marks: HashMap<SshConfigItem, String>) {
+        self.marks = marks;
+    }
+
+    /// Sets the key bindings, the default preset being used otherwise.
+    pub fn set_keymap(&mut self, keymap: Keymap<SelectorAction>) {
+        self.keymap = keymap;
+    }
+
+    /// Sets the styles, the default dark theme being used otherwise.
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+    }
+
+    /// Starts the selector searching for `query`, unless it's empty.
+    pub fn set_query(&mut self, query: &str) {
+        if !query.is_empty() {
+            self.input_buffer.set_value(query);
+            self.mode = Mode::Search;
+        }
+    }
+
+    /// Shows a message in place of the key bindings until the next key press.
+    pub fn set_status(&mut self, status: impl Into<SecretString>) {
+        self.status = Some(status.into());
+    }
+
+    /// Sets whether revealing a password asks for a confirmation first, which is the default.
+    pub fn set_confirm_reveal(&mut self, confirm: bool) {
+        self.confirm_reveal = confirm;
+    }
+
+    fn highlighted(&self) -> Option<SshConfigItem> {
+        self.filtered_data.get(self.state.selected()?).cloned()
+    }
+
     /// Renders the TUI and handles user inputs.
     pub fn select(
         &mut self,
         terminal: &mut Terminal<impl Write>,
-    ) -> anyhow::Result<Option<SshConfigItem>> {
-        let mut selected: Option<SshConfigItem> = None;
+    ) -> anyhow::Result<Option<Selection>> {
+        let mut selected: Option<Selection> = None;
         loop {
             self.draw(terminal)?;
             if let Event::Key(key) = event::read()? {
                 use event::KeyCode::*;
-                match key.code {
-                    Down => self.down(),
-                    Up => self.up(),
-                    Enter => {
+                self.status = None;
+                if let Some(selection) = self.confirming.take() {
+                    if key.code == Char('y') {
+                        selected = Some(selection);
+                        terminal.clear()?;
+                        break;
+                    }
+                    continue;
+                }
+                // any key closes the help
+                if self.help {
+                    self.help = false;
+                    continue;
+                }
+
+                let chord = KeyChord::from_event(&key);
+                let searching = matches!(self.mode, Mode::Search);
+                // the keys typing text edit the query, unless they complete a binding
+                if searching && self.pending.is_empty() && chord.is_text() {
+                    self.input_buffer.handle_event(Event::Key(key));
+                    continue;
+                }
+                self.pending.push(chord);
+                let action = match self.keymap.lookup(&self.pending) {
+                    Lookup::Action(action) => action,
+                    Lookup::Pending => continue,
+                    Lookup::Unbound => {
+                        if searching && self.pending.len() == 1 {
+                            self.input_buffer.handle_event(Event::Key(key));
+                        }
+                        self.pending.clear();
+                        continue;
+                    }
+                };
+                self.pending.clear();
+
+                match action {
+                    SelectorAction::Up => self.up(),
+                    SelectorAction::Down => self.down(),
+                    SelectorAction::Top => self.top(),
+                    SelectorAction::Bottom => self.bottom(),
+                    SelectorAction::Connect => {
                         // If no host is selected, do nothing
-                        if self.state.selected().is_none() {
-                            continue;
-                        } else {
-                            // The unwrap is safe because we checked if the selected index is None.
-                            selected = self
-                                .filtered_data
-                                .get(self.state.selected().unwrap())
-                                .cloned();
+                        if let Some(item) = self.highlighted() {
+                            selected = Some(Selection::Connect(item));
                             // clear the current buffer
                             terminal.clear()?;
                             break;
                         }
                     }
-                    _ => {
-                        if matches!(self.mode, Mode::Normal) {
-                            match key.code {
-                                Esc => {
-                                    terminal.clear()?;
-                                    break;
-                                }
-                                Char('/') => {
-                                    self.mode = Mode::Search;
-                                    self.input_buffer.reset();
-                                }
-
-                                _ => {}
-                            }
-                        } else {
-                            match key.code {
-                                Esc => {
-                                    self.input_buffer.reset();
-                                    self.mode = Mode::Normal;
-                                }
-                                _ => {
-                                    self.input_buffer.handle_event(Event::Key(key));
-                                }
-                            }
+                    SelectorAction::Quit if searching => {
+                        self.input_buffer.reset();
+                        self.mode = Mode::Normal;
+                    }
+                    SelectorAction::Quit => {
+                        terminal.clear()?;
+                        break;
+                    }
+                    SelectorAction::Search => {
+                        if !searching {
+                            self.mode = Mode::Search;
+                            self.input_buffer.reset();
+                        }
+                    }
+                    SelectorAction::Edit => {
+                        if let Some(item) = self.highlighted() {
+                            selected = Some(Selection::Edit(item));
+                            terminal.clear()?;
+                            break;
+                        }
+                    }
+                    SelectorAction::Reveal if self.confirm_reveal => {
+                        if let Some(item) = self.highlighted() {
+                            self.set_status(format!(
+                                "Reveal the password of `{}`? (y/n)",
+                                item.host
+                            ));
+                            self.confirming = Some(Selection::Reveal(item));
+                        }
+                    }
+                    SelectorAction::Reveal => {
+                        if let Some(item) = self.highlighted() {
+                            selected = Some(Selection::Reveal(item));
+                            terminal.clear()?;
+                            break;
+                        }
+                    }
+                    SelectorAction::Forget => {
+                        if let Some(item) = self.highlighted() {
+                            self.set_status(format!(
+                                "Forget the password of `{}`? (y/n)",
+                                item.host
+                            ));
+                            self.confirming = Some(Selection::Forget(item));
                         }
                     }
+                    SelectorAction::Help => self.help = true,
                 }
             }
         }
@@ -135,11 +281,11 @@ impl SelectBox {
 
     fn ui(&mut self, f: &mut Frame) {
         let header = Row::new(vec![
-            Cell::from("Host").style(Style::default().add_modifier(Modifier::UNDERLINED)),
-            Cell::from("User").style(Style::default().add_modifier(Modifier::UNDERLINED)),
-            Cell::from("Hostname").style(Style::default().add_modifier(Modifier::UNDERLINED)),
-        ])
-        .style(Style::default().add_modifier(Modifier::BOLD));
+            Cell::from("Host").style(self.theme.header),
+            Cell::from("User").style(self.theme.header),
+            Cell::from("Hostname").style(self.theme.header),
+            Cell::from("Stored").style(self.theme.header),
+        ]);
 
         // if the input buffer is empty, show all the data
         let rows: Vec<_> = if self.input_buffer.input.value().is_empty() {
@@ -151,6 +297,7 @@ impl SelectBox {
                         Cell::from(d.host.as_str()),
                         Cell::from(d.user.as_str()),
                         Cell::from(d.hostname.as_str()),
+                        Cell::from(self.mark(d)),
                     ])
                 })
                 .collect()
@@ -158,22 +305,26 @@ impl SelectBox {
             // if the input buffer is not empty, show the filtered and highlighted data
             let matches = self.fuzzy_match();
             self.filtered_data = matches.iter().map(|(config, _)| config.clone()).collect();
+            let matched = self.theme.matched;
             matches
                 .iter()
                 .map(|(config, indices)| {
                     let host = Text::from(Line::from(Self::get_highlight_spans(
                         &config.host,
                         &indices[0],
+                        matched,
                     )));
                     let user = Text::from(Line::from(Self::get_highlight_spans(
                         &config.user,
                         &indices[1],
+                        matched,
                     )));
                     let hostname = Text::from(Line::from(Self::get_highlight_spans(
                         &config.hostname,
                         &indices[2],
+                        matched,
                     )));
-                    Row::new([host, user, hostname])
+                    Row::new([host, user, hostname, Text::from(self.mark(config))])
                 })
                 .collect()
         };
@@ -185,18 +336,21 @@ impl SelectBox {
             [
                 Constraint::Length(self.longest_item_lens.0 + 1),
                 Constraint::Min(self.longest_item_lens.1 + 1),
-                Constraint::Min(self.longest_item_lens.2),
+                Constraint::Min(self.longest_item_lens.2 + 1),
+                Constraint::Length(self.longest_mark_len()),
             ],
         )
         .header(header)
-        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .highlight_style(self.theme.selection)
         .highlight_spacing(HighlightSpacing::Always);
 
-        let info = if matches!(self.mode, Mode::Normal) {
-            Paragraph::new(Line::from(INFO_TEXT_NORMAL_MODE)).centered()
+        let info = if let Some(status) = self.status.as_ref() {
+            // borrowed, so that no copy of a revealed password is left behind
+            Paragraph::new(Line::from(status.as_str())).centered()
         } else {
-            Paragraph::new(Line::from(INFO_TEXT_SEAERCH_MODE)).centered()
-        };
+            Paragraph::new(Line::from(self.hints())).centered()
+        }
+        .style(self.theme.status);
 
         if matches!(self.mode, Mode::Search) {
             let recs = Layout::vertical([
@@ -206,10 +360,9 @@ impl SelectBox {
             ])
             .split(f.size());
 
-            let input = Paragraph::new(
-                Text::from(self.input_buffer.value()).style(Style::default().fg(Color::Cyan)),
-            )
-            .block(Block::default().borders(Borders::ALL));
+            let input =
+                Paragraph::new(Text::from(self.input_buffer.value()).style(self.theme.input))
+                    .block(Block::default().borders(Borders::ALL));
 
             StatefulWidget::render(table, recs[0], f.buffer_mut(), &mut self.state);
             input.render(recs[1], f.buffer_mut());
@@ -229,6 +382,114 @@ impl SelectBox {
             StatefulWidget::render(table, recs[0], f.buffer_mut(), &mut self.state);
             info.render(recs[1], f.buffer_mut());
         }
+
+        if self.help {
+            self.render_help(f);
+        }
+    }
+
+    /// Returns the bindings of the main actions of the mode, e.g. `(Esc) quit | (↑) move up`.
+    fn hints(&self) -> String {
+        let searching = matches!(self.mode, Mode::Search);
+        let actions = if searching {
+            HINTS_SEARCH_MODE
+        } else {
+            HINTS_NORMAL_MODE
+        };
+        actions
+            .iter()
+            .filter_map(|action| {
+                // the keys typing text edit the query when searching
+                let keys = self
+                    .keymap
+                    .sequences(*action)
+                    .find(|keys| !searching || !keys.chords()[0].is_text())?;
+                let description = match action {
+                    SelectorAction::Quit if searching => "quit search",
+                    action => action.description(),
+                };
+                Some(format!("({}) {}", keys, description))
+            })
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+
+    /// Renders every binding of the keymap in a box over the selector, in as many columns as fit
+    /// since the selector is only as high as the list of hosts.
+    fn render_help(&self, f: &mut Frame) {
+        let help = self.keymap.help();
+        let keys_len = help
+            .iter()
+            .map(|(keys, _)| UnicodeWidthStr::width(keys.as_str()))
+            .max()
+            .unwrap_or(0);
+        let entries: Vec<String> = help
+            .iter()
+            .map(|(keys, action)| {
+                format!(
+                    "{}{}  {}",
+                    keys,
+                    " ".repeat(keys_len - UnicodeWidthStr::width(keys.as_str())),
+                    action.description()
+                )
+            })
+            .collect();
+        let entry_len = entries
+            .iter()
+            .map(|entry| UnicodeWidthStr::width(entry.as_str()))
+            .max()
+            .unwrap_or(0)
+            + 3;
+
+        let area = f.size();
+        let columns = (area.width.saturating_sub(4) as usize / entry_len).max(1);
+        let lines: Vec<Line> = entries
+            .chunks(columns)
+            .map(|row| {
+                let row: Vec<String> = row
+                    .iter()
+                    .map(|entry| {
+                        let padding = entry_len - UnicodeWidthStr::width(entry.as_str());
+                        format!("{}{}", entry, " ".repeat(padding))
+                    })
+                    .collect();
+                Line::from(row.concat())
+            })
+            .collect();
+
+        let popup = Paragraph::new(lines).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(" Key bindings, keys typing text edit the query when searching (any key to close) ")
+                .padding(Padding::horizontal(1)),
+        );
+        Clear.render(area, f.buffer_mut());
+        popup.render(area, f.buffer_mut());
+    }
+
+    fn mark(&self, item: &SshConfigItem) -> String {
+        self.marks.get(item).cloned().unwrap_or_default()
+    }
+
+    fn longest_mark_len(&self) -> u16 {
+        self.marks
+            .values()
+            .map(|mark| UnicodeWidthStr::width(mark.as_str()))
+            .chain(std::iter::once("Stored".len()))
+            .max()
+            .unwrap_or(0) as u16
+    }
+
+    fn top(&mut self) {
+        if self.selected > 0 {
+            self.state.select(Some(0));
+        }
+    }
+
+    fn bottom(&mut self) {
+        if self.selected > 0 {
+            self.state.select(Some(self.selected - 1));
+        }
     }
 
     fn up(&mut self) {
@@ -261,59 +522,20 @@ impl SelectBox {
 
     // return order: host, user, hostname
     fn fuzzy_match(&self) -> Vec<(SshConfigItem, [Vec<usize>; 3])> {
-        let matcher = SkimMatcherV2::default();
-        let pattern = self.input_buffer.input.value();
-        let choices: Vec<(SshConfigItem, [Vec<usize>; 3])> = self
-            .data
-            .iter()
-            .filter_map(|config| {
-                let mut host_matched_indices = Vec::new();
-                let mut user_matched_indices = Vec::new();
-                let mut hostname_matched_indices = Vec::new();
-
-                if let Some((_, indices)) = matcher.fuzzy_indices(&config.host, pattern) {
-                    host_matched_indices = indices;
-                }
-
-                if let Some((_, indices)) = matcher.fuzzy_indices(&config.user, pattern) {
-                    user_matched_indices = indices;
-                }
-
-                if let Some((_, indices)) = matcher.fuzzy_indices(&config.hostname, pattern) {
-                    hostname_matched_indices = indices;
-                }
-
-                if host_matched_indices.is_empty()
-                    && user_matched_indices.is_empty()
-                    && hostname_matched_indices.is_empty()
-                {
-                    return None;
-                } else {
-                    return Some((
-                        config.clone(),
-                        [
-                            host_matched_indices,
-                            user_matched_indices,
-                            hostname_matched_indices,
-                        ],
-                    ));
-                }
-            })
-            .collect();
-        choices
+        fuzzy_match(&self.data, self.input_buffer.input.value())
     }
 
-    fn get_highlight_spans<'b>(input: &str, indices: &[usize]) -> Vec<Span<'b>> {
+    fn get_highlight_spans<'b>(
+        input: &str,
+        indices: &[usize],
+        highlight_style: Style,
+    ) -> Vec<Span<'b>> {
         let mut spans = Vec::new();
         let mut current_segment = String::new();
         let mut index_set: Vec<usize> = indices.to_vec();
         index_set.sort_unstable();
         index_set.dedup();
 
-        let highlight_style = Style::default()
-            .fg(Color::Rgb(250, 0, 0))
-            .bg(Color::Rgb(0xFF, 0xFC, 0x67))
-            .add_modifier(Modifier::BOLD);
         for (i, c) in input.chars().enumerate() {
             if index_set.contains(&i) {
                 if !current_segment.is_empty() {
@@ -333,3 +555,53 @@ impl SelectBox {
         spans
     }
 }
+
+/// Returns the hosts matching `pattern` in the selector, in their order, along with the indices
+/// of the matched characters of their host, user and hostname.
+///
+/// Every host matches an empty pattern.
+pub fn fuzzy_match(data: &[SshConfigItem], pattern: &str) -> Vec<(SshConfigItem, [Vec<usize>; 3])> {
+    if pattern.is_empty() {
+        return data
+            .iter()
+            .map(|config| (config.clone(), Default::default()))
+            .collect();
+    }
+
+    let matcher = SkimMatcherV2::default();
+    data.iter()
+        .filter_map(|config| {
+            let mut host_matched_indices = Vec::new();
+            let mut user_matched_indices = Vec::new();
+            let mut hostname_matched_indices = Vec::new();
+
+            if let Some((_, indices)) = matcher.fuzzy_indices(&config.host, pattern) {
+                host_matched_indices = indices;
+            }
+
+            if let Some((_, indices)) = matcher.fuzzy_indices(&config.user, pattern) {
+                user_matched_indices = indices;
+            }
+
+            if let Some((_, indices)) = matcher.fuzzy_indices(&config.hostname, pattern) {
+                hostname_matched_indices = indices;
+            }
+
+            if host_matched_indices.is_empty()
+                && user_matched_indices.is_empty()
+                && hostname_matched_indices.is_empty()
+            {
+                None
+            } else {
+                Some((
+                    config.clone(),
+                    [
+                        host_matched_indices,
+                        user_matched_indices,
+                        hostname_matched_indices,
+                    ],
+                ))
+            }
+        })
+        .collect()
+}
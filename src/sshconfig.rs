@@ -1,7 +1,16 @@
+use std::{fs::File, io::BufReader, path::PathBuf};
+
 use serde::{Deserialize, Serialize};
 use ssh2_config::{ParseRule, SshConfig};
 use whoami::username;
 
+use crate::app::expand_tilde;
+
+/// The SSH configuration file read when the fssh configuration lists no other.
+pub const DEFAULT_SSH_CONFIG: &str = "~/.ssh/config";
+/// The port used when the configuration doesn't set one.
+pub const DEFAULT_PORT: u16 = 22;
+
 #[derive(Deserialize, Serialize, Clone, Debug, Hash, PartialEq, Eq)]
 /// Key elements of an SSH configuration.
 pub struct SshConfigItem {
@@ -11,25 +20,56 @@ pub struct SshConfigItem {
     pub user: String,
     /// IP or DNS.
     pub hostname: String,
+    /// The port of the SSH server.
+    pub port: u16,
+}
+
+impl SshConfigItem {
+    /// Fills a template with the fields of the host: `{host}`, `{user}`, `{hostname}` and `{port}`.
+    pub fn format(&self, template: &str) -> String {
+        template
+            .replace("{host}", &self.host)
+            .replace("{user}", &self.user)
+            .replace("{hostname}", &self.hostname)
+            .replace("{port}", &self.port.to_string())
+    }
 }
 
 /// Reads the default SSH configuration file and retrieves a list of [`SshConfigItem`].
 pub fn retrive_ssh_configs() -> Result<Vec<SshConfigItem>, Box<dyn std::error::Error>> {
-    let config = SshConfig::parse_default_file(ParseRule::STRICT)?;
-
-    let mut datas = Vec::new();
-    for host in config.get_hosts() {
-        // if hostname is not set, we can't connect to it
-        if let Some(hostname) = host.params.host_name.clone() {
-            // if user is not set, we use the current user
-            let user = host.params.user.clone().unwrap_or(username());
-
-            for alias in host.pattern.iter() {
-                datas.push(SshConfigItem {
-                    host: alias.pattern.clone(),
-                    user: user.clone(),
-                    hostname: hostname.clone(),
-                });
+    Result::Ok(read_ssh_configs(&[PathBuf::from(DEFAULT_SSH_CONFIG)])?)
+}
+
+/// Reads the given SSH configuration files in order and retrieves a list of [`SshConfigItem`].
+///
+/// An alias defined in several files is taken from the first one.
+pub fn read_ssh_configs(paths: &[PathBuf]) -> anyhow::Result<Vec<SshConfigItem>> {
+    let mut datas: Vec<SshConfigItem> = Vec::new();
+    for path in paths {
+        let path = expand_tilde(path);
+        let file = File::open(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let config = SshConfig::default()
+            .parse(&mut BufReader::new(file), ParseRule::STRICT)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))?;
+
+        for host in config.get_hosts() {
+            // if hostname is not set, we can't connect to it
+            if let Some(hostname) = host.params.host_name.clone() {
+                // if user is not set, we use the current user
+                let user = host.params.user.clone().unwrap_or(username());
+
+                for alias in host.pattern.iter() {
+                    if datas.iter().any(|item| item.host == alias.pattern) {
+                        continue;
+                    }
+                    datas.push(SshConfigItem {
+                        host: alias.pattern.clone(),
+                        user: user.clone(),
+                        hostname: hostname.clone(),
+                        port: host.params.port.unwrap_or(DEFAULT_PORT),
+                    });
+                }
             }
         }
     }
@@ -0,0 +1,789 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    hash::Hash,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encrypt::{write_atomically, Cipher, EncryptionManager},
+    secret::SecretBytes,
+    sshconfig::{SshConfigItem, DEFAULT_PORT},
+    Db,
+};
+
+const DB_FILE: &str = "db";
+const PASSPHRASE_FILE: &str = "passphrases";
+const TOTP_FILE: &str = "totp";
+const SECRET_FILE: &str = "secrets";
+/// The files of the default store, in the data directory.
+pub(crate) const STORE_FILES: [&str; 4] = [DB_FILE, PASSPHRASE_FILE, TOTP_FILE, SECRET_FILE];
+
+/// The identity of a host in the stores, which doesn't change when [`SshConfigItem`] grows.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HostKey {
+    /// Alias name in the configuration.
+    pub alias: String,
+    /// User name.
+    pub user: String,
+    /// IP or DNS.
+    pub hostname: String,
+    /// The port of the SSH server.
+    pub port: u16,
+}
+
+impl From<&SshConfigItem> for HostKey {
+    fn from(item: &SshConfigItem) -> Self {
+        Self {
+            alias: item.host.clone(),
+            user: item.user.clone(),
+            hostname: item.hostname.clone(),
+            port: item.port,
+        }
+    }
+}
+
+impl fmt::Display for HostKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` ({}@{}:{})",
+            self.alias, self.user, self.hostname, self.port
+        )
+    }
+}
+
+/// When a secret was stored and last used, in seconds since the Unix epoch.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stamps {
+    /// When the secret was stored.
+    pub created: u64,
+    /// When the secret was last used to log in.
+    pub used: u64,
+}
+
+impl Stamps {
+    fn now() -> Self {
+        let now = now();
+        Self {
+            created: now,
+            used: now,
+        }
+    }
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// An encrypted secret of a [`FileStore`] along with its [`Stamps`].
+#[derive(Serialize, Deserialize, Debug)]
+struct Record {
+    data: Vec<u8>,
+    stamps: Stamps,
+}
+
+impl Record {
+    /// Wraps a secret stored before the stamps were recorded, as if it was stored now.
+    fn migrate(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            stamps: Stamps::now(),
+        }
+    }
+}
+
+/// The keys of the host stores in format 0, the fields of [`SshConfigItem`] at the time.
+#[derive(Deserialize, PartialEq, Eq, Hash)]
+struct LegacyHostKey {
+    host: String,
+    user: String,
+    hostname: String,
+}
+
+impl LegacyHostKey {
+    /// Converts the key, taking the port from the matching host of the SSH config if any.
+    fn migrate(self, hosts: &[SshConfigItem]) -> HostKey {
+        let port = hosts
+            .iter()
+            .find(|item| {
+                item.host == self.host && item.user == self.user && item.hostname == self.hostname
+            })
+            .map_or(DEFAULT_PORT, |item| item.port);
+        HostKey {
+            alias: self.host,
+            user: self.user,
+            hostname: self.hostname,
+            port,
+        }
+    }
+}
+
+/// Identifies a secret in a [`SecretStore`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SecretKey {
+    /// The login password of a host.
+    Password(HostKey),
+    /// The passphrase of an identity file.
+    Passphrase(PathBuf),
+    /// The TOTP secret of a host.
+    Totp(HostKey),
+    /// A named secret referred to by login scripts.
+    Named(String),
+}
+
+impl SecretKey {
+    /// Returns a path-like name of the key, e.g. `password/alias/user@hostname`.
+    ///
+    /// The port of the host follows the hostname unless it's the default one, e.g. `user@hostname:2222`.
+    pub fn name(&self) -> String {
+        match self {
+            SecretKey::Password(host) => format!("password/{}", host_name(host)),
+            SecretKey::Passphrase(path) => {
+                format!("passphrase/{}", escape(&path.to_string_lossy()))
+            }
+            SecretKey::Totp(host) => format!("totp/{}", host_name(host)),
+            SecretKey::Named(name) => format!("secret/{}", escape(name)),
+        }
+    }
+
+    /// Parses a name returned by [`SecretKey::name`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        let (kind, rest) = name.split_once('/')?;
+        match kind {
+            "password" => parse_host_name(rest).map(SecretKey::Password),
+            "passphrase" => Some(SecretKey::Passphrase(PathBuf::from(unescape(rest)))),
+            "totp" => parse_host_name(rest).map(SecretKey::Totp),
+            "secret" => Some(SecretKey::Named(unescape(rest))),
+            _ => None,
+        }
+    }
+}
+
+fn host_name(host: &HostKey) -> String {
+    let name = format!(
+        "{}/{}@{}",
+        escape(&host.alias),
+        escape(&host.user),
+        // IPv6 addresses must not be confused with the port
+        escape(&host.hostname).replace(':', "%3A")
+    );
+    if host.port == DEFAULT_PORT {
+        name
+    } else {
+        format!("{}:{}", name, host.port)
+    }
+}
+
+fn parse_host_name(name: &str) -> Option<HostKey> {
+    let (alias, rest) = name.split_once('/')?;
+    let (user, rest) = rest.rsplit_once('@')?;
+    let (hostname, port) = match rest.rsplit_once(':') {
+        Some((hostname, port)) => (hostname, port.parse().ok()?),
+        None => (rest, DEFAULT_PORT),
+    };
+    Some(HostKey {
+        alias: unescape(alias),
+        user: unescape(user),
+        hostname: unescape(hostname),
+        port,
+    })
+}
+
+/// Escapes the characters that can't be part of a path component.
+fn escape(s: &str) -> String {
+    s.replace('%', "%25").replace('/', "%2F")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("%2F", "/")
+        .replace("%3A", ":")
+        .replace("%25", "%")
+}
+
+/// A storage of secrets, the default being the encrypted files of fssh.
+pub trait SecretStore {
+    /// Returns the secret stored for `key`.
+    fn get(&mut self, key: &SecretKey) -> anyhow::Result<Option<SecretBytes>>;
+    /// Stores the secret for `key`, replacing the previous one.
+    fn set(&mut self, key: &SecretKey, secret: &[u8]) -> anyhow::Result<()>;
+    /// Removes the secret stored for `key`, returning whether there was one.
+    fn remove(&mut self, key: &SecretKey) -> anyhow::Result<bool>;
+    /// Returns the keys of all the stored secrets.
+    fn keys(&mut self) -> anyhow::Result<Vec<SecretKey>>;
+    /// Persists the changes.
+    fn flush(&mut self) -> anyhow::Result<()>;
+
+    /// Returns when the secret stored for `key` was stored and last used, if the store records it.
+    fn stamps(&mut self, _key: &SecretKey) -> anyhow::Result<Option<Stamps>> {
+        Ok(None)
+    }
+    /// Records that the secret stored for `key` was used, if the store records it.
+    fn touch(&mut self, _key: &SecretKey) -> anyhow::Result<()> {
+        Ok(())
+    }
+    /// Stores the secret for `key` as if it was stored and last used at `stamps`, if the store
+    /// records it.
+    fn set_with_stamps(
+        &mut self,
+        key: &SecretKey,
+        secret: &[u8],
+        _stamps: Stamps,
+    ) -> anyhow::Result<()> {
+        self.set(key, secret)
+    }
+    /// Moves the secret stored for `from` to `to`, keeping its stamps, returning whether there was one.
+    fn rename(&mut self, from: &SecretKey, to: &SecretKey) -> anyhow::Result<bool> {
+        let Some(secret) = self.get(from)? else {
+            return Ok(false);
+        };
+        match self.stamps(from)? {
+            Some(stamps) => self.set_with_stamps(to, &secret, stamps)?,
+            None => self.set(to, &secret)?,
+        }
+        self.remove(from)
+    }
+}
+
+/// The secret storage backend selected in the `[store]` section of the configuration.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(tag = "backend", rename_all = "lowercase", deny_unknown_fields)]
+pub enum StoreConfig {
+    /// Files in the data directory, encrypted with the fssh key.
+    #[default]
+    File,
+    /// The `pass` password manager (GPG encrypted files).
+    Pass {
+        /// The folder of the fssh entries in the password store.
+        #[serde(default = "default_pass_prefix")]
+        prefix: String,
+    },
+    /// Files encrypted with `age`.
+    Age {
+        /// The recipients the secrets are encrypted to.
+        recipients: Vec<String>,
+        /// The identity file decrypting the secrets.
+        identity: PathBuf,
+        /// The directory of the encrypted files, `age` in the data directory by default.
+        dir: Option<PathBuf>,
+    },
+}
+
+fn default_pass_prefix() -> String {
+    "fssh".to_string()
+}
+
+/// The default store: one [`Db`] per kind of secret, encrypted with a [`Cipher`].
+pub struct FileStore {
+    cipher: Rc<dyn Cipher>,
+    passwords: Db<HostKey, Record>,
+    // passphrases are keyed by the identity file, so they are shared between hosts
+    passphrases: Db<PathBuf, Record>,
+    totp: Db<HostKey, Record>,
+    secrets: Db<String, Record>,
+}
+
+impl FileStore {
+    /// Opens the store files in `dir`.
+    ///
+    /// The files of older formats are migrated, the hosts of the SSH config giving the port of the
+    /// secrets stored before ports were part of the keys. Secrets stored before their [`Stamps`] were
+    /// recorded count as stored when migrated.
+    pub fn open(
+        dir: &Path,
+        cipher: Rc<dyn Cipher>,
+        hosts: &[SshConfigItem],
+    ) -> anyhow::Result<Self> {
+        let migrate_hosts = |version, data: &[u8]| -> anyhow::Result<HashMap<HostKey, Record>> {
+            if version != 0 {
+                anyhow::bail!("Unknown format.");
+            }
+            let legacy: HashMap<LegacyHostKey, Vec<u8>> = bincode::deserialize(data)?;
+            Ok(legacy
+                .into_iter()
+                .map(|(key, data)| (key.migrate(hosts), Record::migrate(data)))
+                .collect())
+        };
+
+        Ok(Self {
+            cipher,
+            passwords: Db::open_with(dir.join(DB_FILE), migrate_hosts)?,
+            passphrases: Db::open_with(dir.join(PASSPHRASE_FILE), migrate_records)?,
+            totp: Db::open_with(dir.join(TOTP_FILE), migrate_hosts)?,
+            secrets: Db::open_with(dir.join(SECRET_FILE), migrate_records)?,
+        })
+    }
+
+    /// Re-encrypts every secret with `manager`, which becomes the cipher of the store.
+    ///
+    /// Secrets already encrypted with `manager` are kept as is, so an interrupted rotation can be resumed.
+    pub fn rotate(&mut self, manager: Rc<EncryptionManager>) -> anyhow::Result<()> {
+        fn reencrypt<K: Hash + Eq + Clone>(
+            db: &mut Db<K, Record>,
+            old: &dyn Cipher,
+            new: &EncryptionManager,
+        ) -> anyhow::Result<()> {
+            let keys: Vec<K> = db.keys().cloned().collect();
+            for key in keys {
+                let record = &db[&key];
+                if EncryptionManager::key_id_of(&record.data) == Some(new.key_id()) {
+                    continue;
+                }
+                let record = Record {
+                    data: new.encrypt(&old.decrypt(&record.data)?)?,
+                    stamps: record.stamps,
+                };
+                db.insert(key, record);
+            }
+            Ok(())
+        }
+
+        let old = self.cipher.as_ref();
+        reencrypt(&mut self.passwords, old, &manager)?;
+        reencrypt(&mut self.passphrases, old, &manager)?;
+        reencrypt(&mut self.totp, old, &manager)?;
+        reencrypt(&mut self.secrets, old, &manager)?;
+        self.cipher = manager;
+        Ok(())
+    }
+
+    fn insert(&mut self, key: &SecretKey, record: Record) {
+        match key {
+            SecretKey::Password(item) => self.passwords.insert(item.clone(), record),
+            SecretKey::Passphrase(path) => self.passphrases.insert(path.clone(), record),
+            SecretKey::Totp(item) => self.totp.insert(item.clone(), record),
+            SecretKey::Named(name) => self.secrets.insert(name.clone(), record),
+        };
+    }
+
+    fn take(&mut self, key: &SecretKey) -> Option<Record> {
+        match key {
+            SecretKey::Password(item) => self.passwords.remove(item),
+            SecretKey::Passphrase(path) => self.passphrases.remove(path),
+            SecretKey::Totp(item) => self.totp.remove(item),
+            SecretKey::Named(name) => self.secrets.remove(name),
+        }
+    }
+
+    fn record(&self, key: &SecretKey) -> Option<&Record> {
+        match key {
+            SecretKey::Password(item) => self.passwords.get(item),
+            SecretKey::Passphrase(path) => self.passphrases.get(path),
+            SecretKey::Totp(item) => self.totp.get(item),
+            SecretKey::Named(name) => self.secrets.get(name),
+        }
+    }
+}
+
+impl SecretStore for FileStore {
+    fn get(&mut self, key: &SecretKey) -> anyhow::Result<Option<SecretBytes>> {
+        match self.record(key) {
+            Some(record) => Ok(Some(self.cipher.decrypt(&record.data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&mut self, key: &SecretKey, secret: &[u8]) -> anyhow::Result<()> {
+        self.set_with_stamps(key, secret, Stamps::now())
+    }
+
+    fn remove(&mut self, key: &SecretKey) -> anyhow::Result<bool> {
+        Ok(self.take(key).is_some())
+    }
+
+    fn keys(&mut self) -> anyhow::Result<Vec<SecretKey>> {
+        let passwords = self.passwords.keys().cloned().map(SecretKey::Password);
+        let passphrases = self.passphrases.keys().cloned().map(SecretKey::Passphrase);
+        let totp = self.totp.keys().cloned().map(SecretKey::Totp);
+        let secrets = self.secrets.keys().cloned().map(SecretKey::Named);
+        Ok(passwords
+            .chain(passphrases)
+            .chain(totp)
+            .chain(secrets)
+            .collect())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.passwords.flush()?;
+        self.passphrases.flush()?;
+        self.totp.flush()?;
+        self.secrets.flush()
+    }
+
+    fn stamps(&mut self, key: &SecretKey) -> anyhow::Result<Option<Stamps>> {
+        Ok(self.record(key).map(|record| record.stamps))
+    }
+
+    fn touch(&mut self, key: &SecretKey) -> anyhow::Result<()> {
+        let Some(record) = self.record(key) else {
+            return Ok(());
+        };
+        let record = Record {
+            data: record.data.clone(),
+            stamps: Stamps {
+                used: now(),
+                ..record.stamps
+            },
+        };
+        self.insert(key, record);
+        Ok(())
+    }
+
+    fn set_with_stamps(
+        &mut self,
+        key: &SecretKey,
+        secret: &[u8],
+        stamps: Stamps,
+    ) -> anyhow::Result<()> {
+        let record = Record {
+            data: self.cipher.encrypt(secret)?,
+            stamps,
+        };
+        self.insert(key, record);
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &SecretKey, to: &SecretKey) -> anyhow::Result<bool> {
+        // the record is moved as is, without decrypting it
+        let Some(record) = self.take(from) else {
+            return Ok(false);
+        };
+        self.insert(to, record);
+        Ok(true)
+    }
+}
+
+/// Converts the stores keyed by identity files and names, whose keys didn't change, to [`Record`]s.
+fn migrate_records<K>(version: u16, data: &[u8]) -> anyhow::Result<HashMap<K, Record>>
+where
+    K: Hash + Eq + for<'de> Deserialize<'de>,
+{
+    match version {
+        0 => {
+            let db: HashMap<K, Vec<u8>> = bincode::deserialize(data)?;
+            Ok(db
+                .into_iter()
+                .map(|(key, data)| (key, Record::migrate(data)))
+                .collect())
+        }
+        _ => anyhow::bail!("Unknown format."),
+    }
+}
+
+/// Runs `cmd`, feeding `input` to its stdin, and returns its stdout.
+fn run(cmd: &mut Command, input: Option<&[u8]>) -> anyhow::Result<SecretBytes> {
+    let program = cmd.get_program().to_string_lossy().to_string();
+    let mut child = cmd
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to run `{}`: {}", program, e))?;
+
+    if let Some(input) = input {
+        // the unwrap is safe because stdin is piped
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(input)?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "`{}` failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(SecretBytes::new(output.stdout))
+}
+
+/// Returns the names of the files with the given extension under `dir`, relative and without the extension.
+fn walk(dir: &Path, extension: &str) -> anyhow::Result<Vec<String>> {
+    fn visit(
+        root: &Path,
+        dir: &Path,
+        extension: &str,
+        names: &mut Vec<String>,
+    ) -> anyhow::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                visit(root, &path, extension, names)?;
+            } else if path.extension().is_some_and(|e| e == extension) {
+                // the unwrap is safe because the path is under root
+                let name = path.strip_prefix(root).unwrap().with_extension("");
+                names.push(name.to_string_lossy().to_string());
+            }
+        }
+        Ok(())
+    }
+
+    let mut names = Vec::new();
+    if dir.exists() {
+        visit(dir, dir, extension, &mut names)?;
+    }
+    Ok(names)
+}
+
+/// Secrets kept in the `pass` password manager, under a prefix folder.
+pub struct PassStore {
+    prefix: String,
+}
+
+impl PassStore {
+    /// Creates a [`PassStore`] keeping the entries under `prefix`.
+    pub fn new(prefix: String) -> Self {
+        Self { prefix }
+    }
+
+    fn entry(&self, key: &SecretKey) -> String {
+        format!("{}/{}", self.prefix, key.name())
+    }
+
+    fn store_dir() -> PathBuf {
+        std::env::var_os("PASSWORD_STORE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| dirs::home_dir().unwrap().join(".password-store"))
+    }
+}
+
+impl SecretStore for PassStore {
+    fn get(&mut self, key: &SecretKey) -> anyhow::Result<Option<SecretBytes>> {
+        let file = Self::store_dir().join(format!("{}.gpg", self.entry(key)));
+        if !file.exists() {
+            return Ok(None);
+        }
+        let mut secret = run(Command::new("pass").arg("show").arg(self.entry(key)), None)?;
+        // pass ends the entries with a newline
+        if secret.last() == Some(&b'\n') {
+            secret.pop();
+        }
+        Ok(Some(secret))
+    }
+
+    fn set(&mut self, key: &SecretKey, secret: &[u8]) -> anyhow::Result<()> {
+        run(
+            Command::new("pass")
+                .args(["insert", "--multiline", "--force"])
+                .arg(self.entry(key)),
+            Some(secret),
+        )?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &SecretKey) -> anyhow::Result<bool> {
+        if self.get(key)?.is_none() {
+            return Ok(false);
+        }
+        run(
+            Command::new("pass")
+                .args(["rm", "--force"])
+                .arg(self.entry(key)),
+            None,
+        )?;
+        Ok(true)
+    }
+
+    fn keys(&mut self) -> anyhow::Result<Vec<SecretKey>> {
+        Ok(walk(&Self::store_dir().join(&self.prefix), "gpg")?
+            .iter()
+            .filter_map(|name| SecretKey::from_name(name))
+            .collect())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Secrets kept in files encrypted with `age`.
+pub struct AgeStore {
+    recipients: Vec<String>,
+    identity: PathBuf,
+    dir: PathBuf,
+}
+
+impl AgeStore {
+    /// Creates an [`AgeStore`] keeping the files in `dir`.
+    pub fn new(recipients: Vec<String>, identity: PathBuf, dir: PathBuf) -> Self {
+        Self {
+            recipients,
+            identity,
+            dir,
+        }
+    }
+
+    fn file(&self, key: &SecretKey) -> PathBuf {
+        self.dir.join(format!("{}.age", key.name()))
+    }
+}
+
+impl SecretStore for AgeStore {
+    fn get(&mut self, key: &SecretKey) -> anyhow::Result<Option<SecretBytes>> {
+        let file = self.file(key);
+        if !file.exists() {
+            return Ok(None);
+        }
+        let secret = run(
+            Command::new("age")
+                .arg("--decrypt")
+                .arg("--identity")
+                .arg(&self.identity)
+                .arg(file),
+            None,
+        )?;
+        Ok(Some(secret))
+    }
+
+    fn set(&mut self, key: &SecretKey, secret: &[u8]) -> anyhow::Result<()> {
+        let file = self.file(key);
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut cmd = Command::new("age");
+        cmd.arg("--encrypt");
+        for recipient in self.recipients.iter() {
+            cmd.arg("--recipient").arg(recipient);
+        }
+        let encrypted = run(&mut cmd, Some(secret))?;
+
+        // write then rename, so that a failure never leaves a truncated file behind
+        write_atomically(&file, &encrypted)
+    }
+
+    fn remove(&mut self, key: &SecretKey) -> anyhow::Result<bool> {
+        let file = self.file(key);
+        if !file.exists() {
+            return Ok(false);
+        }
+        fs::remove_file(file)?;
+        Ok(true)
+    }
+
+    fn keys(&mut self) -> anyhow::Result<Vec<SecretKey>> {
+        Ok(walk(&self.dir, "age")?
+            .iter()
+            .filter_map(|name| SecretKey::from_name(name))
+            .collect())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{format_version, tests::test_dir, DB_VERSION};
+
+    /// Stores the secrets as is, the migrations don't decrypt them.
+    struct Plain;
+
+    impl Cipher for Plain {
+        fn encrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+            Ok(data.to_vec())
+        }
+
+        fn decrypt(&self, data: &[u8]) -> anyhow::Result<SecretBytes> {
+            Ok(SecretBytes::new(data.to_vec()))
+        }
+    }
+
+    fn host(alias: &str, port: u16) -> HostKey {
+        HostKey {
+            alias: alias.to_string(),
+            user: "admin".to_string(),
+            hostname: format!("{}.example.com", alias),
+            port,
+        }
+    }
+
+    #[test]
+    fn rename_keeps_the_stamps() {
+        let dir = test_dir("store-rename");
+        let mut store = FileStore::open(&dir, Rc::new(Plain), &[]).unwrap();
+        let (old, new) = (
+            SecretKey::Password(host("old", 22)),
+            SecretKey::Password(host("new", 22)),
+        );
+        let stamps = Stamps {
+            created: 1000,
+            used: 2000,
+        };
+        store.set_with_stamps(&old, b"secret", stamps).unwrap();
+
+        assert!(store.rename(&old, &new).unwrap());
+        assert!(!store.rename(&old, &new).unwrap());
+        assert_eq!(store.get(&old).unwrap(), None);
+        assert_eq!(*store.get(&new).unwrap().unwrap(), b"secret");
+        assert_eq!(store.stamps(&new).unwrap(), Some(stamps));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migrates_v0_host_store_with_the_port_of_the_ssh_config() {
+        #[derive(Serialize, PartialEq, Eq, Hash)]
+        struct V0Key {
+            host: String,
+            user: String,
+            hostname: String,
+        }
+
+        let dir = test_dir("store-v0");
+        let v0 = HashMap::from([
+            (
+                V0Key {
+                    host: "db".to_string(),
+                    user: "admin".to_string(),
+                    hostname: "db.example.com".to_string(),
+                },
+                b"two".to_vec(),
+            ),
+            (
+                V0Key {
+                    host: "gone".to_string(),
+                    user: "admin".to_string(),
+                    hostname: "gone.example.com".to_string(),
+                },
+                b"three".to_vec(),
+            ),
+        ]);
+        fs::write(dir.join(DB_FILE), bincode::serialize(&v0).unwrap()).unwrap();
+        let hosts = [SshConfigItem {
+            host: "db".to_string(),
+            user: "admin".to_string(),
+            hostname: "db.example.com".to_string(),
+            port: 2222,
+        }];
+
+        let before = now();
+        let mut store = FileStore::open(&dir, Rc::new(Plain), &hosts).unwrap();
+        let db = SecretKey::Password(host("db", 2222));
+        let gone = SecretKey::Password(host("gone", DEFAULT_PORT));
+        assert_eq!(*store.get(&db).unwrap().unwrap(), b"two");
+        assert_eq!(*store.get(&gone).unwrap().unwrap(), b"three");
+        // secrets stored before the stamps count as stored when migrated
+        let stamps = store.stamps(&db).unwrap().unwrap();
+        assert!(stamps.created >= before && stamps.used == stamps.created);
+        assert!(dir.join("db.v0").exists());
+        assert_eq!(
+            format_version(&dir.join(DB_FILE)).unwrap(),
+            Some(DB_VERSION)
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,350 @@
+use ratatui::style::{Color, Modifier, Style};
+use serde::Deserialize;
+
+/// The colors of the 16 colors palette, as rendered by xterm.
+const ANSI_COLORS: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+/// The levels of each channel in the 6x6x6 cube of the 256 colors palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// The colors a terminal can show.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSupport {
+    /// Detected from `NO_COLOR`, `COLORTERM` and `TERM`.
+    #[default]
+    Auto,
+    /// Any RGB color.
+    TrueColor,
+    /// The 256 colors palette.
+    #[serde(rename = "256")]
+    Ansi256,
+    /// The 16 colors palette.
+    #[serde(rename = "16")]
+    Ansi16,
+    /// No color, only bold, underlined and reversed text.
+    None,
+}
+
+impl ColorSupport {
+    /// Returns the colors supported by the terminal, following the `NO_COLOR` convention.
+    pub fn detect() -> Self {
+        let var = |name| std::env::var(name).unwrap_or_default();
+        if !var("NO_COLOR").is_empty() || var("TERM") == "dumb" {
+            Self::None
+        } else if matches!(var("COLORTERM").as_str(), "truecolor" | "24bit") {
+            Self::TrueColor
+        } else if var("TERM").contains("256color") {
+            Self::Ansi256
+        } else {
+            Self::Ansi16
+        }
+    }
+
+    /// Returns the closest color the terminal can show, `None` if it shows no color.
+    pub fn convert(self, color: Color) -> Option<Color> {
+        match (self, color) {
+            (Self::Auto, color) => Self::detect().convert(color),
+            (Self::None, _) => None,
+            (_, Color::Reset) | (Self::TrueColor, _) => Some(color),
+            (Self::Ansi256, Color::Rgb(r, g, b)) => Some(Color::Indexed(to_256((r, g, b)))),
+            (Self::Ansi256, color) => Some(color),
+            (Self::Ansi16, Color::Rgb(r, g, b)) => Some(to_16((r, g, b))),
+            (Self::Ansi16, Color::Indexed(i)) => Some(to_16(indexed_rgb(i))),
+            (Self::Ansi16, color) => Some(color),
+        }
+    }
+
+    /// Returns the style with its colors converted, see [`ColorSupport::convert`].
+    pub fn adapt(self, style: Style) -> Style {
+        let support = match self {
+            Self::Auto => Self::detect(),
+            support => support,
+        };
+        Style {
+            fg: style.fg.and_then(|color| support.convert(color)),
+            bg: style.bg.and_then(|color| support.convert(color)),
+            ..style
+        }
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+/// Returns the closest color of the 256 colors palette: in the color cube or the gray ramp.
+fn to_256(rgb: (u8, u8, u8)) -> u8 {
+    let level = |c: u8| {
+        (0..CUBE_LEVELS.len())
+            .min_by_key(|i| (CUBE_LEVELS[*i] as i32 - c as i32).abs())
+            .unwrap_or(0) as u8
+    };
+    let (r, g, b) = (level(rgb.0), level(rgb.1), level(rgb.2));
+    let cube = 16 + 36 * r + 6 * g + b;
+
+    let average = (rgb.0 as u32 + rgb.1 as u32 + rgb.2 as u32) / 3;
+    let gray = ((average.saturating_sub(8) + 5) / 10).min(23) as u8;
+    let gray_level = 8 + 10 * gray;
+
+    if distance(rgb, (gray_level, gray_level, gray_level)) < distance(rgb, indexed_rgb(cube)) {
+        232 + gray
+    } else {
+        cube
+    }
+}
+
+/// Returns the closest color of the 16 colors palette.
+fn to_16(rgb: (u8, u8, u8)) -> Color {
+    ANSI_COLORS
+        .iter()
+        .min_by_key(|(_, ansi)| distance(rgb, *ansi))
+        .map_or(Color::Reset, |(color, _)| *color)
+}
+
+/// Returns the RGB value of a color of the 256 colors palette.
+fn indexed_rgb(index: u8) -> (u8, u8, u8) {
+    match index {
+        0..=15 => ANSI_COLORS[index as usize].1,
+        16..=231 => {
+            let i = index - 16;
+            (
+                CUBE_LEVELS[(i / 36) as usize],
+                CUBE_LEVELS[(i / 6 % 6) as usize],
+                CUBE_LEVELS[(i % 6) as usize],
+            )
+        }
+        _ => {
+            let level = 8 + 10 * (index - 232);
+            (level, level, level)
+        }
+    }
+}
+
+/// The built-in themes, which the configuration can override per element.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThemePreset {
+    /// For terminals with a dark background.
+    #[default]
+    Dark,
+    /// For terminals with a light background.
+    Light,
+}
+
+/// The styles of the selector and of the terminal view.
+#[derive(Debug, Clone, Copy)]
+pub struct Theme {
+    /// The highlighted host.
+    pub selection: Style,
+    /// The characters matching the search query.
+    pub matched: Style,
+    /// The column titles.
+    pub header: Style,
+    /// The key hints and messages at the bottom.
+    pub status: Style,
+    /// The search query.
+    pub input: Style,
+    /// The default colors of the terminal view, used where the session sets none.
+    pub terminal: Style,
+}
+
+impl Theme {
+    /// Returns the styles of a preset.
+    pub fn preset(preset: ThemePreset) -> Self {
+        let selection = Style::default().add_modifier(Modifier::REVERSED);
+        let header = Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED);
+        let status = Style::default();
+        match preset {
+            ThemePreset::Dark => Self {
+                selection,
+                matched: Style::default()
+                    .fg(Color::Rgb(250, 0, 0))
+                    .bg(Color::Rgb(0xFF, 0xFC, 0x67))
+                    .add_modifier(Modifier::BOLD),
+                header,
+                status,
+                input: Style::default().fg(Color::Cyan),
+                terminal: Style::default().bg(Color::Black),
+            },
+            ThemePreset::Light => Self {
+                selection,
+                matched: Style::default()
+                    .fg(Color::Rgb(0xB0, 0, 0))
+                    .bg(Color::Rgb(0xFF, 0xE0, 0x82))
+                    .add_modifier(Modifier::BOLD),
+                header,
+                status,
+                input: Style::default().fg(Color::Blue),
+                terminal: Style::default().fg(Color::Black).bg(Color::White),
+            },
+        }
+    }
+
+    /// Returns the theme with the colors the terminal can show.
+    pub fn adapt(self, support: ColorSupport) -> Self {
+        Self {
+            selection: support.adapt(self.selection),
+            matched: support.adapt(self.matched),
+            header: support.adapt(self.header),
+            status: support.adapt(self.status),
+            input: support.adapt(self.input),
+            terminal: support.adapt(self.terminal),
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::preset(ThemePreset::default()).adapt(ColorSupport::Auto)
+    }
+}
+
+/// A color of the configuration: a name like `red` or `darkgray`, an index of the 256 colors
+/// palette or `#rrggbb`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ThemeColor(Color);
+
+impl TryFrom<String> for ThemeColor {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+            .map(Self)
+            .map_err(|_| anyhow::anyhow!("Invalid color `{}`.", s))
+    }
+}
+
+/// Changes to the style of an element of the theme, the unset attributes being kept.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct StyleConfig {
+    /// The foreground color.
+    pub fg: Option<ThemeColor>,
+    /// The background color.
+    pub bg: Option<ThemeColor>,
+    /// Whether the text is bold.
+    pub bold: Option<bool>,
+    /// Whether the text is italic.
+    pub italic: Option<bool>,
+    /// Whether the text is underlined.
+    pub underlined: Option<bool>,
+    /// Whether the foreground and background colors are swapped.
+    pub reversed: Option<bool>,
+}
+
+impl StyleConfig {
+    /// Returns `style` with the attributes set in the configuration.
+    pub fn apply(&self, mut style: Style) -> Style {
+        if let Some(ThemeColor(color)) = self.fg {
+            style.fg = Some(color);
+        }
+        if let Some(ThemeColor(color)) = self.bg {
+            style.bg = Some(color);
+        }
+        for (enabled, modifier) in [
+            (self.bold, Modifier::BOLD),
+            (self.italic, Modifier::ITALIC),
+            (self.underlined, Modifier::UNDERLINED),
+            (self.reversed, Modifier::REVERSED),
+        ] {
+            match enabled {
+                Some(true) => {
+                    style.add_modifier.insert(modifier);
+                    style.sub_modifier.remove(modifier);
+                }
+                Some(false) => style.add_modifier.remove(modifier),
+                None => {}
+            }
+        }
+        style
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_to_the_256_colors_palette() {
+        for (rgb, index) in [
+            ((0, 0, 0), 16),
+            ((255, 255, 255), 231),
+            ((255, 0, 0), 196),
+            ((95, 135, 175), 67),
+            ((100, 140, 170), 67),
+            ((128, 128, 128), 244),
+            ((18, 18, 18), 233),
+            ((240, 240, 240), 255),
+        ] {
+            assert_eq!(to_256(rgb), index, "{:?}", rgb);
+        }
+        for index in 16..=255 {
+            assert_eq!(to_256(indexed_rgb(index)), index);
+        }
+    }
+
+    #[test]
+    fn maps_to_the_16_colors_palette() {
+        for (rgb, color) in [
+            ((0, 0, 0), Color::Black),
+            ((190, 20, 10), Color::Red),
+            ((250, 10, 10), Color::LightRed),
+            ((0, 0, 200), Color::Blue),
+            ((100, 100, 250), Color::LightBlue),
+            ((128, 128, 128), Color::DarkGray),
+            ((220, 220, 220), Color::Gray),
+            ((250, 250, 250), Color::White),
+        ] {
+            assert_eq!(to_16(rgb), color, "{:?}", rgb);
+        }
+        for (color, rgb) in ANSI_COLORS {
+            assert_eq!(to_16(rgb), color);
+        }
+    }
+
+    #[test]
+    fn converts_to_the_supported_colors() {
+        let rgb = Color::Rgb(255, 0, 0);
+        for (support, color, converted) in [
+            (ColorSupport::TrueColor, rgb, Some(rgb)),
+            (ColorSupport::Ansi256, rgb, Some(Color::Indexed(196))),
+            (ColorSupport::Ansi256, Color::Red, Some(Color::Red)),
+            (ColorSupport::Ansi16, rgb, Some(Color::LightRed)),
+            (ColorSupport::Ansi16, Color::Indexed(22), Some(Color::Black)),
+            (
+                ColorSupport::Ansi16,
+                Color::Indexed(244),
+                Some(Color::DarkGray),
+            ),
+            (ColorSupport::Ansi16, Color::Reset, Some(Color::Reset)),
+            (ColorSupport::None, Color::Red, None),
+        ] {
+            assert_eq!(
+                support.convert(color),
+                converted,
+                "{:?} {:?}",
+                support,
+                color
+            );
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use zeroize::Zeroizing;
+
+use crate::secret::SecretBytes;
+
+const DIGITS: u32 = 6;
+const PERIOD: u64 = 30;
+
+/// A time-based one-time password generator as defined in RFC 6238 (HMAC-SHA1, 6 digits, 30s).
+#[derive(Clone)]
+pub struct Totp {
+    secret: SecretBytes,
+}
+
+impl Totp {
+    /// Creates a [`Totp`] instance from the raw shared secret.
+    pub fn new(secret: SecretBytes) -> Self {
+        Self { secret }
+    }
+
+    /// Creates a [`Totp`] instance from a base32 encoded secret as shown by most enrollment pages.
+    ///
+    /// Whitespaces, padding and lowercase letters are accepted.
+    pub fn from_base32(secret: &str) -> anyhow::Result<Self> {
+        let normalized: Zeroizing<String> = Zeroizing::new(
+            secret
+                .chars()
+                .filter(|c| !c.is_whitespace() && *c != '=')
+                .map(|c| c.to_ascii_uppercase())
+                .collect(),
+        );
+        let secret = SecretBytes::new(
+            BASE32_NOPAD
+                .decode(normalized.as_bytes())
+                .map_err(|e| anyhow::anyhow!("Invalid base32 secret: {}", e))?,
+        );
+        if secret.is_empty() {
+            anyhow::bail!("The secret is empty.");
+        }
+        Ok(Self::new(secret))
+    }
+
+    /// Returns the raw shared secret.
+    pub fn secret(&self) -> &[u8] {
+        &self.secret
+    }
+
+    /// Generates the code for the current time.
+    pub fn now(&self) -> anyhow::Result<String> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(self.generate(timestamp))
+    }
+
+    /// Generates the code for the given unix timestamp.
+    pub fn generate(&self, timestamp: u64) -> String {
+        let counter = timestamp / PERIOD;
+        // the unwrap is safe because HMAC accepts keys of any length
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).unwrap();
+        mac.update(&counter.to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+
+        // dynamic truncation, see RFC 4226 section 5.3
+        let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([
+            digest[offset] & 0x7f,
+            digest[offset + 1],
+            digest[offset + 2],
+            digest[offset + 3],
+        ]);
+        format!(
+            "{:0width$}",
+            binary % 10u32.pow(DIGITS),
+            width = DIGITS as usize
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 test vectors of RFC 6238 appendix B, truncated to the last 6 digits.
+    #[test]
+    fn rfc6238_sha1_vectors() {
+        let totp = Totp::new(SecretBytes::new(b"12345678901234567890".to_vec()));
+        for (timestamp, code) in [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ] {
+            assert_eq!(totp.generate(timestamp), code[2..], "T = {}", timestamp);
+        }
+    }
+
+    #[test]
+    fn base32_secret() {
+        // "12345678901234567890" in base32, lowercase with spaces and padding
+        let totp = Totp::from_base32("gezd gnbv gy3t qojq gezd gnbv gy3t qojq ====").unwrap();
+        assert_eq!(totp.secret(), b"12345678901234567890");
+        assert!(Totp::from_base32("not base32!").is_err());
+        assert!(Totp::from_base32("").is_err());
+    }
+}